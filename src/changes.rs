@@ -0,0 +1,550 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{Change, Error, Hash, Object, ObjectMode, Result, Storage};
+
+// one file that differs between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub change: Change,
+    pub old_path: String,
+    pub new_path: String,
+    // None on the side the file is missing from
+    pub old_hash: Option<Hash>,
+    pub new_hash: Option<Hash>,
+    // how alike the two sides of a rename or copy are, in percent
+    pub similarity: Option<u8>,
+}
+
+// how `detect_renames` pairs up files
+#[derive(Debug, Clone, Copy)]
+pub struct RenameOptions {
+    pub enabled: bool,
+    // the similarity in percent from which a deleted and an added file
+    // count as a rename
+    pub threshold: u8,
+    // also look for added files copied from modified or deleted ones
+    pub copies: bool,
+}
+
+impl Default for RenameOptions {
+    // git's defaults: renames at 50% similarity, no copies
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 50,
+            copies: false,
+        }
+    }
+}
+
+impl FileChange {
+    fn new(
+        path: &str,
+        old_hash: Option<&Hash>,
+        new_hash: Option<&Hash>,
+    ) -> Self {
+        let change = match (old_hash, new_hash) {
+            (None, _) => Change::Added,
+            (_, None) => Change::Deleted,
+            _ => Change::Modified,
+        };
+        Self {
+            change,
+            old_path: path.to_string(),
+            new_path: path.to_string(),
+            old_hash: old_hash.cloned(),
+            new_hash: new_hash.cloned(),
+            similarity: None,
+        }
+    }
+
+    // a rename or copy of `from`'s old side to `to`'s new side
+    fn paired(
+        change: Change,
+        from: &FileChange,
+        to: &FileChange,
+        similarity: u8,
+    ) -> Self {
+        Self {
+            change,
+            old_path: from.old_path.clone(),
+            new_path: to.new_path.clone(),
+            old_hash: from.old_hash.clone(),
+            new_hash: to.new_hash.clone(),
+            similarity: Some(similarity),
+        }
+    }
+
+    // the path to show for the change, the new one unless deleted
+    pub fn path(&self) -> &str {
+        match self.change {
+            Change::Deleted => &self.old_path,
+            _ => &self.new_path,
+        }
+    }
+}
+
+// the changes between two flat path-to-blob maps, sorted by path
+pub fn diff_files(
+    old: &BTreeMap<String, Hash>,
+    new: &BTreeMap<String, Hash>,
+) -> Vec<FileChange> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(|path| FileChange::new(path, old.get(path), new.get(path)))
+        .collect()
+}
+
+// the changes between two trees (None for an empty one), sorted by path.
+// Subtrees with the same id on both sides are skipped without reading them.
+pub fn diff_trees(
+    storage: &impl Storage,
+    old: Option<&Hash>,
+    new: Option<&Hash>,
+) -> Result<Vec<FileChange>> {
+    let mut changes = Vec::new();
+    diff_subtrees(storage, old, new, "", &mut changes)?;
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+// a tree's entries by name: (is a tree, id), leaving out submodules
+fn tree_entries(
+    storage: &impl Storage,
+    hash: Option<&Hash>,
+) -> Result<BTreeMap<String, (bool, Hash)>> {
+    let Some(hash) = hash else {
+        return Ok(BTreeMap::new());
+    };
+    let Object::Tree { contents, .. } = storage.read_object(hash)? else {
+        return Err(Error::CorruptObject(hash.clone()));
+    };
+
+    Ok(contents
+        .into_iter()
+        .filter_map(|(name, mode, hash)| match mode {
+            ObjectMode::Blob => Some((name, (false, hash))),
+            ObjectMode::Tree => Some((name, (true, hash))),
+            ObjectMode::Commit => None,
+        })
+        .collect())
+}
+
+fn diff_subtrees(
+    storage: &impl Storage,
+    old: Option<&Hash>,
+    new: Option<&Hash>,
+    prefix: &str,
+    changes: &mut Vec<FileChange>,
+) -> Result<()> {
+    if old == new {
+        return Ok(());
+    }
+
+    let old = tree_entries(storage, old)?;
+    let new = tree_entries(storage, new)?;
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for name in names {
+        let path = format!("{}{}", prefix, name);
+        let (old, new) = (old.get(name), new.get(name));
+        if old == new {
+            continue;
+        }
+
+        // a name can be a file on one side and a directory on the other
+        let old_tree = old
+            .filter(|(is_tree, _)| *is_tree)
+            .map(|(_, hash)| hash);
+        let new_tree = new
+            .filter(|(is_tree, _)| *is_tree)
+            .map(|(_, hash)| hash);
+        let old_blob = old
+            .filter(|(is_tree, _)| !is_tree)
+            .map(|(_, hash)| hash);
+        let new_blob = new
+            .filter(|(is_tree, _)| !is_tree)
+            .map(|(_, hash)| hash);
+
+        if old_tree.is_some() || new_tree.is_some() {
+            diff_subtrees(
+                storage,
+                old_tree,
+                new_tree,
+                &format!("{}/", path),
+                changes,
+            )?;
+        }
+        if old_blob.is_some() || new_blob.is_some() {
+            changes.push(FileChange::new(&path, old_blob, new_blob));
+        }
+    }
+    Ok(())
+}
+
+// the content of the blob `hash`, empty for None
+pub fn blob_content(
+    storage: &impl Storage,
+    hash: Option<&Hash>,
+) -> Result<Vec<u8>> {
+    let Some(hash) = hash else {
+        return Ok(Vec::new());
+    };
+    match storage.read_object(hash)? {
+        Object::Blob { content, .. } => Ok(content),
+        _ => Err(Error::CorruptObject(hash.clone())),
+    }
+}
+
+// how much of the larger of two contents the other one shares, in percent,
+// counting the bytes of the lines they have in common
+pub fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    if old.is_empty() || new.is_empty() {
+        return if old == new { 100 } else { 0 };
+    }
+
+    let mut lines: HashMap<&[u8], usize> = HashMap::new();
+    for line in old.split_inclusive(|&byte| byte == b'\n') {
+        *lines.entry(line).or_default() += 1;
+    }
+    let mut common = 0;
+    for line in new.split_inclusive(|&byte| byte == b'\n') {
+        if let Some(count) = lines.get_mut(line)
+            && *count > 0
+        {
+            *count -= 1;
+            common += line.len();
+        }
+    }
+
+    (common * 100 / old.len().max(new.len())) as u8
+}
+
+// pair deleted with added files into renames, first those with the same
+// content and then, best match first, those at least `threshold` similar;
+// with `copies`, remaining added files are matched against the old side of
+// every changed file. Empty files are never paired, as in git. `content`
+// reads a change's new side when given true, its old side otherwise. The
+// result is sorted by path.
+pub fn detect_renames(
+    changes: Vec<FileChange>,
+    options: &RenameOptions,
+    content: impl Fn(&FileChange, bool) -> Result<Vec<u8>>,
+) -> Result<Vec<FileChange>> {
+    if !options.enabled {
+        return Ok(changes);
+    }
+
+    let mut added = Vec::new();
+    let mut deleted = Vec::new();
+    let mut result = Vec::new();
+    for change in changes {
+        match change.change {
+            Change::Added => added.push(change),
+            Change::Deleted => deleted.push(change),
+            _ => result.push(change),
+        }
+    }
+
+    // (added, deleted) indices that became renames
+    let mut renames: Vec<(usize, usize, u8)> = Vec::new();
+    let mut added_paired = vec![false; added.len()];
+    let mut deleted_paired = vec![false; deleted.len()];
+
+    // every empty file has the same id, so the same content says nothing
+    let empty = Some(Object::blob(Vec::new()).hash());
+    for (a, add) in added.iter().enumerate() {
+        if add.new_hash == empty {
+            continue;
+        }
+        if let Some(d) = (0..deleted.len()).find(|&d| {
+            !deleted_paired[d] && deleted[d].old_hash == add.new_hash
+        }) {
+            renames.push((a, d, 100));
+            added_paired[a] = true;
+            deleted_paired[d] = true;
+        }
+    }
+
+    let added_content = added
+        .iter()
+        .enumerate()
+        .map(|(a, add)| match added_paired[a] {
+            true => Ok(Vec::new()),
+            false => content(add, true),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let deleted_content = deleted
+        .iter()
+        .map(|del| content(del, false))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut candidates = Vec::new();
+    let unpaired_added: Vec<usize> = (0..added.len())
+        .filter(|&a| !added_paired[a] && !added_content[a].is_empty())
+        .collect();
+    for &a in &unpaired_added {
+        for d in (0..deleted.len()).filter(|&d| !deleted_paired[d]) {
+            if deleted_content[d].is_empty() {
+                continue;
+            }
+            let score = similarity(&deleted_content[d], &added_content[a]);
+            if score >= options.threshold {
+                candidates.push((a, d, score));
+            }
+        }
+    }
+    // best first, ties in path order
+    candidates.sort_by_key(|&(a, d, score)| (std::cmp::Reverse(score), a, d));
+    for (a, d, score) in candidates {
+        if !added_paired[a] && !deleted_paired[d] {
+            renames.push((a, d, score));
+            added_paired[a] = true;
+            deleted_paired[d] = true;
+        }
+    }
+
+    let mut copies: Vec<(usize, FileChange, u8)> = Vec::new();
+    if options.copies {
+        let modified: Vec<&FileChange> = result
+            .iter()
+            .filter(|change| change.change == Change::Modified)
+            .collect();
+        let modified_content = modified
+            .iter()
+            .map(|change| content(change, false))
+            .collect::<Result<Vec<_>>>()?;
+        let sources: Vec<(&FileChange, &[u8])> = deleted
+            .iter()
+            .zip(&deleted_content)
+            .chain(
+                modified
+                    .into_iter()
+                    .zip(&modified_content),
+            )
+            .map(|(change, content)| (change, content.as_slice()))
+            .filter(|(_, content)| !content.is_empty())
+            .collect();
+
+        for a in unpaired_added {
+            if added_paired[a] {
+                continue;
+            }
+            let best = sources
+                .iter()
+                .map(|(source, source_content)| {
+                    let score = if source.old_hash == added[a].new_hash {
+                        100
+                    } else {
+                        similarity(source_content, &added_content[a])
+                    };
+                    (score, *source)
+                })
+                .filter(|(score, _)| *score >= options.threshold)
+                .max_by_key(|(score, _)| *score);
+            if let Some((score, source)) = best {
+                copies.push((a, source.clone(), score));
+                added_paired[a] = true;
+            }
+        }
+    }
+
+    for (a, d, score) in renames {
+        result.push(FileChange::paired(
+            Change::Renamed,
+            &deleted[d],
+            &added[a],
+            score,
+        ));
+    }
+    for (a, source, score) in copies {
+        result.push(FileChange::paired(
+            Change::Copied,
+            &source,
+            &added[a],
+            score,
+        ));
+    }
+    for (a, add) in added.into_iter().enumerate() {
+        if !added_paired[a] {
+            result.push(add);
+        }
+    }
+    for (d, del) in deleted.into_iter().enumerate() {
+        if !deleted_paired[d] {
+            result.push(del);
+        }
+    }
+
+    result.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the file changes from `old` to `new`, each a list of (path, content)
+    struct Snapshots {
+        changes: Vec<FileChange>,
+        contents: HashMap<Hash, Vec<u8>>,
+    }
+
+    fn snapshots(old: &[(&str, &str)], new: &[(&str, &str)]) -> Snapshots {
+        let mut contents = HashMap::new();
+        let mut files = |files: &[(&str, &str)]| -> BTreeMap<String, Hash> {
+            files
+                .iter()
+                .map(|(path, content)| {
+                    let content = content.as_bytes().to_vec();
+                    let hash = Object::blob(content.clone()).hash();
+                    contents.insert(hash.clone(), content);
+                    (path.to_string(), hash)
+                })
+                .collect()
+        };
+        let (old, new) = (files(old), files(new));
+        Snapshots {
+            changes: diff_files(&old, &new),
+            contents,
+        }
+    }
+
+    impl Snapshots {
+        // one "<code>[<similarity>]\t<path>[\t<new path>]" line per change
+        fn detect(&self, options: &RenameOptions) -> Vec<String> {
+            let changes =
+                detect_renames(self.changes.clone(), options, |change, new| {
+                    let hash = match new {
+                        true => &change.new_hash,
+                        false => &change.old_hash,
+                    };
+                    Ok(self.contents[hash.as_ref().unwrap()].clone())
+                })
+                .unwrap();
+            changes
+                .into_iter()
+                .map(|change| match change.similarity {
+                    Some(score) => format!(
+                        "{}{}\t{}\t{}",
+                        change.change.code(),
+                        score,
+                        change.old_path,
+                        change.new_path
+                    ),
+                    None => {
+                        format!("{}\t{}", change.change.code(), change.path())
+                    }
+                })
+                .collect()
+        }
+    }
+
+    fn threshold(threshold: u8) -> RenameOptions {
+        RenameOptions {
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn similarity_counts_the_bytes_of_shared_lines() {
+        assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(similarity(b"aaaa\nbbbb\n", b"aaaa\ncccc\n"), 50);
+        // measured against the larger side
+        assert_eq!(similarity(b"aaaa\n", b"aaaa\nbbbbbbbbbbbbbb\n"), 25);
+        assert_eq!(similarity(b"", b"a\n"), 0);
+    }
+
+    #[test]
+    fn the_same_content_pairs_before_a_similar_one() {
+        // "new" is an exact copy of "exact" but also close to "close"
+        let snapshots = snapshots(
+            &[("close", "1\n2\n3\n4\n5\n"), ("exact", "1\n2\n3\n4\n")],
+            &[("new", "1\n2\n3\n4\n"), ("other", "1\n2\n3\n4\n6\n")],
+        );
+        assert_eq!(
+            snapshots.detect(&RenameOptions::default()),
+            ["R100\texact\tnew", "R80\tclose\tother"]
+        );
+    }
+
+    #[test]
+    fn the_best_match_pairs_first() {
+        let snapshots = snapshots(
+            &[("a", "1\n2\n3\n4\n5\n"), ("b", "1\n2\n3\n4\n9\n")],
+            &[("c", "1\n2\n3\n8\n9\n"), ("d", "1\n2\n3\n4\n0\n")],
+        );
+        // b-c, a-d and b-d all score 80; ties go in path order, so c
+        // takes b and leaves a to d
+        assert_eq!(
+            snapshots.detect(&RenameOptions::default()),
+            ["R80\tb\tc", "R80\ta\td"]
+        );
+    }
+
+    #[test]
+    fn the_threshold_is_inclusive() {
+        let snapshots =
+            snapshots(&[("old", "aaaa\nbbbb\n")], &[("new", "aaaa\ncccc\n")]);
+        assert_eq!(snapshots.detect(&threshold(50)), ["R50\told\tnew"]);
+        assert_eq!(snapshots.detect(&threshold(51)), ["A\tnew", "D\told"]);
+
+        let off = RenameOptions {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(snapshots.detect(&off), ["A\tnew", "D\told"]);
+    }
+
+    #[test]
+    fn empty_files_are_never_paired() {
+        let snapshots = snapshots(
+            &[("gone", ""), ("kept", "k\n")],
+            &[("added", ""), ("kept", "k\nl\n")],
+        );
+        let copies = RenameOptions {
+            copies: true,
+            ..threshold(0)
+        };
+        for options in [RenameOptions::default(), threshold(0), copies] {
+            assert_eq!(
+                snapshots.detect(&options),
+                ["A\tadded", "D\tgone", "M\tkept"]
+            );
+        }
+    }
+
+    #[test]
+    fn copies_come_from_modified_or_deleted_files() {
+        let snapshots = snapshots(
+            &[("gone", "1\n2\n3\n4\n"), ("src", "a\nb\nc\nd\n")],
+            &[
+                ("copy", "a\nb\nc\nd\n"),
+                ("moved", "1\n2\n3\n4\n"),
+                ("second", "1\n2\n3\n5\n"),
+                ("src", "a\nb\nc\ne\n"),
+            ],
+        );
+        assert_eq!(
+            snapshots.detect(&RenameOptions::default()),
+            ["A\tcopy", "R100\tgone\tmoved", "A\tsecond", "M\tsrc"]
+        );
+
+        // a deleted file can be both renamed and copied
+        let copies = RenameOptions {
+            copies: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            snapshots.detect(&copies),
+            [
+                "C100\tsrc\tcopy",
+                "R100\tgone\tmoved",
+                "C75\tgone\tsecond",
+                "M\tsrc",
+            ]
+        );
+    }
+}
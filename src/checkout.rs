@@ -0,0 +1,435 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use vfs::{FileSystem, VfsFileType};
+
+use crate::{
+    Entry, Error, Hash, Object, Result, Storage, in_mount,
+    storage::create_dir_all,
+};
+
+// the blob id of the worktree file at `path` ("dir/file"), None when there
+// is no file there
+pub fn worktree_hash(fs: &dyn FileSystem, path: &str) -> Result<Option<Hash>> {
+    let path = format!("/{}", path);
+    if !fs.exists(&path)? || fs.metadata(&path)?.file_type != VfsFileType::File
+    {
+        return Ok(None);
+    }
+
+    let mut content = Vec::new();
+    fs.open_file(&path)?
+        .read_to_end(&mut content)?;
+    Ok(Some(Object::blob(content).hash()))
+}
+
+// write the blob `hash` to the worktree at `path`, creating its directories
+pub fn write_worktree_file(
+    storage: &impl Storage,
+    fs: &dyn FileSystem,
+    path: &str,
+    hash: &Hash,
+) -> Result<()> {
+    let Object::Blob { content, .. } = storage.read_object(hash)? else {
+        return Err(Error::CorruptObject(hash.clone()));
+    };
+    write_worktree_content(fs, path, &content)
+}
+
+// write `content` to the worktree at `path`, creating its directories
+pub fn write_worktree_content(
+    fs: &dyn FileSystem,
+    path: &str,
+    content: &[u8],
+) -> Result<()> {
+    let path = format!("/{}", path);
+    if let Some(parent) = Path::new(&path).parent() {
+        create_dir_all(fs, parent)?;
+    }
+    fs.create_file(&path)?
+        .write_all(content)?;
+    Ok(())
+}
+
+// delete the worktree file at `path` along with any directories that
+// leaves empty
+pub fn remove_worktree_file(fs: &dyn FileSystem, path: &str) -> Result<()> {
+    let path = format!("/{}", path);
+    if fs.exists(&path)? {
+        fs.remove_file(&path)?;
+    }
+
+    let mut dir = Path::new(&path).parent();
+    while let Some(parent) = dir {
+        let parent_str = parent.to_string_lossy();
+        if parent_str == "/"
+            || !fs.exists(&parent_str)?
+            || fs
+                .read_dir(&parent_str)?
+                .next()
+                .is_some()
+        {
+            break;
+        }
+        fs.remove_dir(&parent_str)?;
+        dir = parent.parent();
+    }
+    Ok(())
+}
+
+// move the worktree and index from HEAD's files to `target`. Only paths
+// that differ between the two are touched, so local changes elsewhere
+// carry over; a changed path with local modifications, or an untracked
+// file in the way, fails with `Error::Conflict` before anything is
+// written. `force` discards local changes instead. An index with unmerged
+// paths fails with `Error::Unmerged` unless forced.
+pub fn checkout_files(
+    storage: &mut impl Storage,
+    fs: &dyn FileSystem,
+    target: &BTreeMap<String, Hash>,
+    force: bool,
+) -> Result<()> {
+    let unmerged: Vec<String> = storage
+        .index()
+        .unmerged()
+        .into_iter()
+        .map(|unmerged| unmerged.path)
+        .collect();
+    if !unmerged.is_empty() && !force {
+        return Err(Error::Unmerged(unmerged));
+    }
+
+    // a tree from elsewhere could name files in the repository itself
+    if let Some(path) = target
+        .keys()
+        .find(|path| in_mount(path))
+    {
+        return Err(Error::InvalidPath(path.clone()));
+    }
+
+    let current = storage.head_files()?;
+    let index: BTreeMap<String, Hash> = storage
+        .index()
+        .entries()
+        .iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.file_name().to_string(), entry.blob_hash().clone()))
+        .collect();
+
+    let mut paths: BTreeSet<&String> = current
+        .keys()
+        .chain(target.keys())
+        .collect();
+    if force {
+        paths.extend(index.keys().chain(&unmerged));
+    } else {
+        paths.retain(|path| current.get(*path) != target.get(*path));
+    }
+    // nor is the repository touched through entries an older version
+    // could stage there
+    paths.retain(|path| !in_mount(path));
+
+    let mut worktree = BTreeMap::new();
+    for path in &paths {
+        worktree.insert(*path, worktree_hash(fs, path)?);
+    }
+
+    if !force {
+        let mut modified = Vec::new();
+        let mut untracked = Vec::new();
+        for path in &paths {
+            let (old, new) = (current.get(*path), target.get(*path));
+            let staged = index.get(*path);
+            let on_disk = worktree[path].as_ref();
+
+            let clean = staged == on_disk && (staged == old || staged == new);
+            if clean {
+                continue;
+            }
+            if staged.is_none() && old.is_none() {
+                // an untracked file only matters if it would be replaced
+                if on_disk.is_some() && on_disk != new {
+                    untracked.push(path.as_str());
+                }
+            } else {
+                modified.push(path.as_str());
+            }
+        }
+
+        let mut message = String::new();
+        if !modified.is_empty() {
+            message.push_str(
+                "Your local changes to the following files would be \
+                 overwritten by checkout:\n",
+            );
+            for path in &modified {
+                message.push_str(&format!("\t{}\n", path));
+            }
+            message.push_str(
+                "Please commit your changes before you switch branches.",
+            );
+        }
+        if !untracked.is_empty() {
+            if !message.is_empty() {
+                message.push('\n');
+            }
+            message.push_str(
+                "The following untracked working tree files would be \
+                 overwritten by checkout:\n",
+            );
+            for path in &untracked {
+                message.push_str(&format!("\t{}\n", path));
+            }
+            message.push_str(
+                "Please move or remove them before you switch branches.",
+            );
+        }
+        if !message.is_empty() {
+            return Err(Error::Conflict(message));
+        }
+    }
+
+    // deletions first, so a file can give way to a directory of that name
+    for path in &paths {
+        if !target.contains_key(*path)
+            && (current.contains_key(*path)
+                || index.contains_key(*path)
+                || unmerged.contains(*path))
+        {
+            remove_worktree_file(fs, path)?;
+        }
+    }
+    for path in &paths {
+        if let Some(hash) = target.get(*path)
+            && worktree[path].as_ref() != Some(hash)
+        {
+            write_worktree_file(storage, fs, path, hash)?;
+        }
+    }
+
+    storage.modify_index(|index| {
+        for path in &paths {
+            match target.get(*path) {
+                Some(hash) => {
+                    index.insert(Entry::new(path.to_string(), hash.clone()));
+                }
+                None => {
+                    index.remove(path);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+    use crate::{Content, Expected, JsonStorage, Signature};
+
+    // a repository whose HEAD commit, index and worktree all hold `files`
+    fn repo(files: &[(&str, &str)]) -> (JsonStorage, MemoryFS) {
+        let mut storage = JsonStorage::new();
+        let fs = MemoryFS::new();
+        for (path, content) in files {
+            storage
+                .update_index(Content::new(
+                    path.to_string(),
+                    content.as_bytes().to_vec(),
+                ))
+                .unwrap();
+            write_worktree_content(&fs, path, content.as_bytes()).unwrap();
+        }
+        let tree = storage.write_tree().unwrap();
+        let sig = Signature::new("A".into(), "a@b".into(), 0, 0);
+        let commit = storage
+            .hash_object(Object::commit(
+                "c1".to_string(),
+                tree,
+                Vec::new(),
+                sig.clone(),
+                sig,
+            ))
+            .unwrap();
+        storage
+            .update_ref("refs/heads/main", commit, &Expected::Any)
+            .unwrap();
+        (storage, fs)
+    }
+
+    // the flattened tree holding `files`, its blobs stored
+    fn target(
+        storage: &mut JsonStorage,
+        files: &[(&str, &str)],
+    ) -> BTreeMap<String, Hash> {
+        files
+            .iter()
+            .map(|(path, content)| {
+                let blob = Object::blob(content.as_bytes().to_vec());
+                (path.to_string(), storage.hash_object(blob).unwrap())
+            })
+            .collect()
+    }
+
+    fn read(fs: &MemoryFS, path: &str) -> Option<String> {
+        let path = format!("/{}", path);
+        if !fs.exists(&path).unwrap() {
+            return None;
+        }
+        let mut content = String::new();
+        fs.open_file(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        Some(content)
+    }
+
+    fn staged(storage: &JsonStorage) -> Vec<(String, u8)> {
+        storage
+            .index()
+            .entries()
+            .iter()
+            .map(|entry| (entry.file_name().to_string(), entry.stage()))
+            .collect()
+    }
+
+    fn conflict(result: Result<()>) -> String {
+        match result {
+            Err(Error::Conflict(message)) => message,
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn switching_writes_the_target_and_removes_the_old_files() {
+        let (mut storage, fs) =
+            repo(&[("a", "1"), ("gone/x", "x"), ("same", "s")]);
+        let target =
+            target(&mut storage, &[("a", "2"), ("dir/f", "f"), ("same", "s")]);
+
+        checkout_files(&mut storage, &fs, &target, false).unwrap();
+        assert_eq!(read(&fs, "a").as_deref(), Some("2"));
+        assert_eq!(read(&fs, "dir/f").as_deref(), Some("f"));
+        assert_eq!(read(&fs, "gone/x"), None);
+        // the emptied directory goes with its last file
+        assert!(!fs.exists("/gone").unwrap());
+        assert_eq!(
+            staged(&storage),
+            [("a".into(), 0), ("dir/f".into(), 0), ("same".into(), 0)]
+        );
+    }
+
+    #[test]
+    fn local_changes_carry_over_where_the_trees_agree() {
+        let (mut storage, fs) = repo(&[("a", "1"), ("same", "s")]);
+        write_worktree_content(&fs, "same", b"local").unwrap();
+        let target = target(&mut storage, &[("a", "2"), ("same", "s")]);
+
+        checkout_files(&mut storage, &fs, &target, false).unwrap();
+        assert_eq!(read(&fs, "a").as_deref(), Some("2"));
+        assert_eq!(read(&fs, "same").as_deref(), Some("local"));
+    }
+
+    #[test]
+    fn a_dirty_tracked_file_is_refused() {
+        let (mut storage, fs) = repo(&[("a", "1"), ("b", "1")]);
+        write_worktree_content(&fs, "a", b"local").unwrap();
+        let target = target(&mut storage, &[("a", "2"), ("c", "3")]);
+
+        let message =
+            conflict(checkout_files(&mut storage, &fs, &target, false));
+        assert!(message.contains("local changes"));
+        assert!(message.contains("\ta\n"));
+        // nothing is written before the check
+        assert_eq!(read(&fs, "a").as_deref(), Some("local"));
+        assert_eq!(read(&fs, "b").as_deref(), Some("1"));
+        assert_eq!(read(&fs, "c"), None);
+    }
+
+    #[test]
+    fn an_untracked_file_in_the_way_is_refused() {
+        let (mut storage, fs) = repo(&[("a", "1")]);
+        write_worktree_content(&fs, "new", b"mine").unwrap();
+        let target = target(&mut storage, &[("a", "1"), ("new", "theirs")]);
+
+        let message =
+            conflict(checkout_files(&mut storage, &fs, &target, false));
+        assert!(message.contains("untracked working tree files"));
+        assert!(message.contains("\tnew\n"));
+        assert_eq!(read(&fs, "new").as_deref(), Some("mine"));
+
+        // one already matching the target is no loss
+        write_worktree_content(&fs, "new", b"theirs").unwrap();
+        checkout_files(&mut storage, &fs, &target, false).unwrap();
+        assert_eq!(staged(&storage), [("a".into(), 0), ("new".into(), 0)]);
+    }
+
+    #[test]
+    fn force_overwrites_local_changes() {
+        let (mut storage, fs) = repo(&[("a", "1"), ("b", "1")]);
+        write_worktree_content(&fs, "a", b"local").unwrap();
+        write_worktree_content(&fs, "b", b"local").unwrap();
+        write_worktree_content(&fs, "new", b"mine").unwrap();
+        write_worktree_content(&fs, "staged", b"s").unwrap();
+        storage
+            .update_index(Content::new("staged".into(), b"s".to_vec()))
+            .unwrap();
+        let target =
+            target(&mut storage, &[("a", "2"), ("b", "1"), ("new", "n")]);
+
+        checkout_files(&mut storage, &fs, &target, true).unwrap();
+        assert_eq!(read(&fs, "a").as_deref(), Some("2"));
+        // even where the trees agree
+        assert_eq!(read(&fs, "b").as_deref(), Some("1"));
+        assert_eq!(read(&fs, "new").as_deref(), Some("n"));
+        // a file only staged is dropped with the rest of the index
+        assert_eq!(read(&fs, "staged"), None);
+        assert_eq!(
+            staged(&storage),
+            [("a".into(), 0), ("b".into(), 0), ("new".into(), 0)]
+        );
+    }
+
+    #[test]
+    fn a_target_in_the_repository_directory_is_refused() {
+        let (mut storage, fs) = repo(&[("a", "1")]);
+        let target = target(&mut storage, &[("a", "2"), (".g1t/HEAD", "x")]);
+
+        for force in [false, true] {
+            match checkout_files(&mut storage, &fs, &target, force) {
+                Err(Error::InvalidPath(path)) => assert_eq!(path, ".g1t/HEAD"),
+                other => panic!("expected an invalid path, got {:?}", other),
+            }
+        }
+        assert_eq!(read(&fs, "a").as_deref(), Some("1"));
+        assert_eq!(read(&fs, ".g1t/HEAD"), None);
+    }
+
+    #[test]
+    fn unmerged_paths_are_refused_unless_forced() {
+        let (mut storage, fs) = repo(&[("a", "1")]);
+        let ours = Object::blob(b"ours".to_vec()).hash();
+        let theirs = Object::blob(b"theirs".to_vec()).hash();
+        storage
+            .modify_index(|index| {
+                index.remove("a");
+                index.insert(Entry::at_stage("a".into(), ours, 2));
+                index.insert(Entry::at_stage("a".into(), theirs, 3));
+            })
+            .unwrap();
+        write_worktree_content(&fs, "a", b"<<<<<<< ours").unwrap();
+        let target = target(&mut storage, &[("a", "2")]);
+
+        match checkout_files(&mut storage, &fs, &target, false) {
+            Err(Error::Unmerged(paths)) => assert_eq!(paths, ["a"]),
+            other => panic!("expected unmerged paths, got {:?}", other),
+        }
+
+        checkout_files(&mut storage, &fs, &target, true).unwrap();
+        assert_eq!(read(&fs, "a").as_deref(), Some("2"));
+        assert_eq!(staged(&storage), [("a".into(), 0)]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Signature, parse_date};
+
+// repository settings as flat dotted keys, e.g. "user.name"
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Config {
+    values: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.values
+            .insert(key.into(), value.into());
+    }
+
+    pub fn unset(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.values.iter()
+    }
+
+    // identity for `role` ("author" or "committer"). G1T_<ROLE>_NAME,
+    // G1T_<ROLE>_EMAIL and G1T_<ROLE>_DATE ("<unix seconds> <+hhmm>") take
+    // precedence over user.name, user.email and the current UTC time.
+    pub fn signature(&self, role: &str) -> Signature {
+        let var = |field: &str| {
+            std::env::var(format!("G1T_{}_{}", role.to_uppercase(), field)).ok()
+        };
+
+        let name = var("NAME")
+            .or_else(|| {
+                self.get("user.name")
+                    .map(str::to_string)
+            })
+            .or_else(|| std::env::var("USER").ok())
+            .unwrap_or_else(|| "unknown".to_string());
+        let email = var("EMAIL")
+            .or_else(|| {
+                self.get("user.email")
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| format!("{}@localhost", name));
+
+        let (time, offset) = var("DATE")
+            .and_then(|date| parse_date(&date))
+            .unwrap_or_else(|| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|now| now.as_secs())
+                    .unwrap_or_default();
+                (now as i64, 0)
+            });
+
+        Signature::new(name, email, time, offset)
+    }
+}
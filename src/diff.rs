@@ -0,0 +1,1014 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::{Change, FileChange, Hash, RenameOptions, Result};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    // the shortest edit script, as git does by default
+    #[default]
+    Myers,
+    // anchors on lines that occur once on both sides, which keeps moved
+    // blocks and braces from being matched up with unrelated lines
+    Patience,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiffOptions {
+    // unchanged lines shown around each change
+    pub context: usize,
+    pub algorithm: DiffAlgorithm,
+    pub renames: RenameOptions,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            context: 3,
+            algorithm: DiffAlgorithm::default(),
+            renames: RenameOptions::default(),
+        }
+    }
+}
+
+// one step of an edit script, by zero-based line number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    Context,
+    Delete,
+    Insert,
+}
+
+impl LineKind {
+    pub fn prefix(self) -> char {
+        match self {
+            LineKind::Context => ' ',
+            LineKind::Delete => '-',
+            LineKind::Insert => '+',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HunkLine {
+    pub kind: LineKind,
+    // the line without its newline
+    pub text: String,
+    // false for a last line that does not end in a newline
+    pub newline: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    // one-based first line and line count on each side
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    // e.g. "@@ -1,3 +1,4 @@"; like diff, an empty side gives the line
+    // before it and a single line leaves out its count
+    pub fn header(&self) -> String {
+        let range = |start: usize, len: usize| match len {
+            0 => format!("{},0", start.saturating_sub(1)),
+            1 => start.to_string(),
+            _ => format!("{},{}", start, len),
+        };
+        format!(
+            "@@ -{} +{} @@",
+            range(self.old_start, self.old_len),
+            range(self.new_start, self.new_len)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BlobDiff {
+    // either side looks binary, so only whether they differ is known
+    Binary,
+    // no hunks when the contents are the same
+    Text(Vec<Hunk>),
+}
+
+// git's heuristic: a NUL byte in the first 8000 bytes
+pub fn is_binary(content: &[u8]) -> bool {
+    content
+        .iter()
+        .take(8000)
+        .any(|&byte| byte == 0)
+}
+
+// `content` split after each newline, so a missing final newline shows
+// up as a difference
+pub fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    content
+        .split_inclusive(|&byte| byte == b'\n')
+        .collect()
+}
+
+pub fn diff_blobs(old: &[u8], new: &[u8], options: &DiffOptions) -> BlobDiff {
+    if old == new {
+        return BlobDiff::Text(Vec::new());
+    }
+    if is_binary(old) || is_binary(new) {
+        return BlobDiff::Binary;
+    }
+
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let ops = diff_lines(&old_lines, &new_lines, options.algorithm);
+    BlobDiff::Text(hunks(&ops, &old_lines, &new_lines, options.context))
+}
+
+// write "--- <old>", "+++ <new>" and the hunks, or a line saying binary
+// contents differ. Labels are e.g. "a/file", or "/dev/null" for a side
+// that does not exist.
+pub fn write_unified(
+    out: &mut dyn Write,
+    old_label: &str,
+    new_label: &str,
+    diff: &BlobDiff,
+) -> Result<()> {
+    let hunks = match diff {
+        BlobDiff::Binary => {
+            writeln!(
+                out,
+                "Binary files {} and {} differ",
+                old_label, new_label
+            )?;
+            return Ok(());
+        }
+        BlobDiff::Text(hunks) if hunks.is_empty() => return Ok(()),
+        BlobDiff::Text(hunks) => hunks,
+    };
+
+    writeln!(out, "--- {}", old_label)?;
+    writeln!(out, "+++ {}", new_label)?;
+    for hunk in hunks {
+        writeln!(out, "{}", hunk.header())?;
+        for line in &hunk.lines {
+            writeln!(out, "{}{}", line.kind.prefix(), line.text)?;
+            if !line.newline {
+                writeln!(out, "\\ No newline at end of file")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn diff_lines<T: PartialEq + Eq + std::hash::Hash>(
+    old: &[T],
+    new: &[T],
+    algorithm: DiffAlgorithm,
+) -> Vec<DiffOp> {
+    let mut ops = Vec::new();
+    match algorithm {
+        DiffAlgorithm::Myers => diff_range(old, new, 0, 0, &mut ops, &myers),
+        DiffAlgorithm::Patience => {
+            diff_range(old, new, 0, 0, &mut ops, &patience)
+        }
+    }
+
+    // within each run of changes, deletions come first, as diff prints them
+    for run in ops.split_mut(|op| matches!(op, DiffOp::Equal(..))) {
+        run.sort_by_key(|op| matches!(op, DiffOp::Insert(_)));
+    }
+    ops
+}
+
+// an algorithm diffing `old` against `new`, which start at the given
+// offsets in the whole input, appending to the ops
+type Middle<'a, T> = dyn Fn(&[T], &[T], usize, usize, &mut Vec<DiffOp>) + 'a;
+
+// strip the common prefix and suffix, which every algorithm would match
+// anyway, and hand what is left to `middle`. `old_offset` and
+// `new_offset` are where the slices start in the whole input.
+fn diff_range<T: PartialEq>(
+    old: &[T],
+    new: &[T],
+    old_offset: usize,
+    new_offset: usize,
+    ops: &mut Vec<DiffOp>,
+    middle: &Middle<'_, T>,
+) {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    for i in 0..prefix {
+        ops.push(DiffOp::Equal(old_offset + i, new_offset + i));
+    }
+    middle(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+        old_offset + prefix,
+        new_offset + prefix,
+        ops,
+    );
+    for i in 0..suffix {
+        ops.push(DiffOp::Equal(
+            old_offset + old.len() - suffix + i,
+            new_offset + new.len() - suffix + i,
+        ));
+    }
+}
+
+// Myers' O(ND) algorithm in linear space: strip the common ends, find the
+// middle snake of an optimal path by searching from both ends at once, and
+// diff the halves before and after it recursively
+fn myers<T: PartialEq>(
+    old: &[T],
+    new: &[T],
+    old_offset: usize,
+    new_offset: usize,
+    ops: &mut Vec<DiffOp>,
+) {
+    let max = (old.len() + new.len()).div_ceil(2) + 1;
+    let mut forward = Diagonals::new(max);
+    let mut backward = Diagonals::new(max);
+    conquer(
+        old,
+        new,
+        old_offset,
+        new_offset,
+        &mut forward,
+        &mut backward,
+        ops,
+    );
+}
+
+// the furthest x reached on each diagonal k = x - y, for k in -max..=max
+struct Diagonals {
+    max: isize,
+    x: Vec<usize>,
+}
+
+impl Diagonals {
+    fn new(max: usize) -> Self {
+        Self {
+            max: max as isize,
+            x: vec![0; 2 * max + 1],
+        }
+    }
+}
+
+impl std::ops::Index<isize> for Diagonals {
+    type Output = usize;
+
+    fn index(&self, k: isize) -> &usize {
+        &self.x[(k + self.max) as usize]
+    }
+}
+
+impl std::ops::IndexMut<isize> for Diagonals {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.x[(k + self.max) as usize]
+    }
+}
+
+fn conquer<T: PartialEq>(
+    mut old: &[T],
+    mut new: &[T],
+    mut old_offset: usize,
+    mut new_offset: usize,
+    forward: &mut Diagonals,
+    backward: &mut Diagonals,
+    ops: &mut Vec<DiffOp>,
+) {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(a, b)| a == b)
+        .count();
+    ops.extend(
+        (0..prefix).map(|i| DiffOp::Equal(old_offset + i, new_offset + i)),
+    );
+    (old, new) = (&old[prefix..], &new[prefix..]);
+    (old_offset, new_offset) = (old_offset + prefix, new_offset + prefix);
+
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+
+    if old.is_empty() {
+        ops.extend((0..new.len()).map(|i| DiffOp::Insert(new_offset + i)));
+    } else if new.is_empty() {
+        ops.extend((0..old.len()).map(|i| DiffOp::Delete(old_offset + i)));
+    } else {
+        let (x, y) = middle_snake(old, new, forward, backward);
+        conquer(
+            &old[..x],
+            &new[..y],
+            old_offset,
+            new_offset,
+            forward,
+            backward,
+            ops,
+        );
+        conquer(
+            &old[x..],
+            &new[y..],
+            old_offset + x,
+            new_offset + y,
+            forward,
+            backward,
+            ops,
+        );
+    }
+
+    let (old_end, new_end) = (old_offset + old.len(), new_offset + new.len());
+    ops.extend((0..suffix).map(|i| DiffOp::Equal(old_end + i, new_end + i)));
+}
+
+// a point on an optimal path through `old` and `new`, both non-empty and
+// differing in their first and last items, that splits it about in half:
+// where the furthest reaching paths from the start and from the end meet
+fn middle_snake<T: PartialEq>(
+    old: &[T],
+    new: &[T],
+    forward: &mut Diagonals,
+    backward: &mut Diagonals,
+) -> (usize, usize) {
+    let (n, m) = (old.len(), new.len());
+    // the paths meet on a forward round when the edit distance is odd
+    let delta = n as isize - m as isize;
+    let odd = delta % 2 != 0;
+
+    forward[1] = 0;
+    backward[1] = 0;
+    for d in 0..=(n + m).div_ceil(2) as isize {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x =
+                if k == -d || (k != d && forward[k - 1] < forward[k + 1]) {
+                    forward[k + 1]
+                } else {
+                    forward[k - 1] + 1
+                };
+            let y = (x as isize - k) as usize;
+            let start = (x, y);
+            x += old[x.min(n)..]
+                .iter()
+                .zip(&new[y.min(m)..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            forward[k] = x;
+
+            if odd
+                && (k - delta).abs() < d
+                && forward[k] + backward[delta - k] >= n
+            {
+                return start;
+            }
+        }
+
+        // the same search from the end, x counting items from the back
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x =
+                if k == -d || (k != d && backward[k - 1] < backward[k + 1]) {
+                    backward[k + 1]
+                } else {
+                    backward[k - 1] + 1
+                };
+            let mut y = (x as isize - k) as usize;
+            let same = old[..n - x.min(n)]
+                .iter()
+                .rev()
+                .zip(new[..m - y.min(m)].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            (x, y) = (x + same, y + same);
+            backward[k] = x;
+
+            if !odd
+                && (k - delta).abs() <= d
+                && backward[k] + forward[delta - k] >= n
+            {
+                return (n - x, m - y);
+            }
+        }
+    }
+    unreachable!("the searches from both ends always meet")
+}
+
+// match up lines that occur exactly once on each side, keep the longest
+// run of those in the same order on both, and diff the gaps between them
+// recursively; with nothing to anchor on, fall back to Myers
+fn patience<T: PartialEq + Eq + std::hash::Hash>(
+    old: &[T],
+    new: &[T],
+    old_offset: usize,
+    new_offset: usize,
+    ops: &mut Vec<DiffOp>,
+) {
+    // (count in old, count in new, index in old, index in new)
+    let mut counts: HashMap<&T, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, line) in old.iter().enumerate() {
+        let entry = counts.entry(line).or_default();
+        entry.0 += 1;
+        entry.2 = i;
+    }
+    for (i, line) in new.iter().enumerate() {
+        let entry = counts.entry(line).or_default();
+        entry.1 += 1;
+        entry.3 = i;
+    }
+
+    let mut unique: Vec<(usize, usize)> = counts
+        .values()
+        .filter(|(in_old, in_new, ..)| *in_old == 1 && *in_new == 1)
+        .map(|&(_, _, old_index, new_index)| (new_index, old_index))
+        .collect();
+    unique.sort();
+
+    let anchors = longest_increasing(&unique);
+    if anchors.is_empty() {
+        myers(old, new, old_offset, new_offset, ops);
+        return;
+    }
+
+    let (mut old_at, mut new_at) = (0, 0);
+    for (old_index, new_index) in anchors {
+        diff_range(
+            &old[old_at..old_index],
+            &new[new_at..new_index],
+            old_offset + old_at,
+            new_offset + new_at,
+            ops,
+            &patience,
+        );
+        ops.push(DiffOp::Equal(
+            old_offset + old_index,
+            new_offset + new_index,
+        ));
+        (old_at, new_at) = (old_index + 1, new_index + 1);
+    }
+    diff_range(
+        &old[old_at..],
+        &new[new_at..],
+        old_offset + old_at,
+        new_offset + new_at,
+        ops,
+        &patience,
+    );
+}
+
+// the longest subsequence of `pairs` (sorted by new index, given as
+// (new, old)) whose old indices increase, by patience sorting; returned
+// as (old, new)
+fn longest_increasing(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // the top of each pile, and each card's predecessor on the pile left
+    let mut tops: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (i, &(_, old_index)) in pairs.iter().enumerate() {
+        let pile = tops.partition_point(|&top| pairs[top].1 < old_index);
+        if pile > 0 {
+            previous[i] = Some(tops[pile - 1]);
+        }
+        if pile == tops.len() {
+            tops.push(i);
+        } else {
+            tops[pile] = i;
+        }
+    }
+
+    let mut sequence = Vec::new();
+    let mut card = tops.last().copied();
+    while let Some(i) = card {
+        sequence.push((pairs[i].1, pairs[i].0));
+        card = previous[i];
+    }
+    sequence.reverse();
+    sequence
+}
+
+// group an edit script into hunks with `context` unchanged lines around
+// each change, merging changes whose context would touch
+pub fn hunks(
+    ops: &[DiffOp],
+    old: &[&[u8]],
+    new: &[&[u8]],
+    context: usize,
+) -> Vec<Hunk> {
+    let is_change = |op: &DiffOp| !matches!(op, DiffOp::Equal(..));
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while let Some(first) = ops[i..].iter().position(is_change) {
+        let first = i + first;
+        let start = first.saturating_sub(context).max(i);
+
+        // take in further changes while the gap to them is small enough
+        // for their context to overlap
+        let mut end = first;
+        loop {
+            while end < ops.len() && is_change(&ops[end]) {
+                end += 1;
+            }
+            let gap = ops[end..]
+                .iter()
+                .take_while(|op| !is_change(op))
+                .count();
+            if end + gap < ops.len() && gap <= 2 * context {
+                end += gap;
+            } else {
+                break;
+            }
+        }
+        let stop = (end + context).min(ops.len());
+
+        // lines on each side before the hunk
+        let (old_line, new_line) =
+            ops[..start]
+                .iter()
+                .fold((0, 0), |(o, n), op| match op {
+                    DiffOp::Equal(..) => (o + 1, n + 1),
+                    DiffOp::Delete(_) => (o + 1, n),
+                    DiffOp::Insert(_) => (o, n + 1),
+                });
+        hunks.push(hunk(&ops[start..stop], old, new, old_line, new_line));
+        i = stop;
+    }
+    hunks
+}
+
+// `old_line` and `new_line` are the zero-based lines the ops start at
+fn hunk(
+    ops: &[DiffOp],
+    old: &[&[u8]],
+    new: &[&[u8]],
+    old_line: usize,
+    new_line: usize,
+) -> Hunk {
+    let line = |kind, text: &[u8]| HunkLine {
+        kind,
+        text: String::from_utf8_lossy(text.strip_suffix(b"\n").unwrap_or(text))
+            .into_owned(),
+        newline: text.ends_with(b"\n"),
+    };
+
+    let mut hunk = Hunk {
+        old_start: old_line + 1,
+        old_len: 0,
+        new_start: new_line + 1,
+        new_len: 0,
+        lines: Vec::new(),
+    };
+    for op in ops {
+        match *op {
+            DiffOp::Equal(o, _) => {
+                hunk.old_len += 1;
+                hunk.new_len += 1;
+                hunk.lines
+                    .push(line(LineKind::Context, old[o]));
+            }
+            DiffOp::Delete(o) => {
+                hunk.old_len += 1;
+                hunk.lines
+                    .push(line(LineKind::Delete, old[o]));
+            }
+            DiffOp::Insert(n) => {
+                hunk.new_len += 1;
+                hunk.lines
+                    .push(line(LineKind::Insert, new[n]));
+            }
+        }
+    }
+    hunk
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    // full unified diffs
+    #[default]
+    Patch,
+    // a histogram of changed lines per file
+    Stat,
+    NameOnly,
+    // each path with its one-letter change code
+    NameStatus,
+}
+
+// what a file's diff adds up to, for `--stat`
+#[derive(Debug, Clone)]
+pub struct FileStat {
+    pub path: String,
+    pub insertions: usize,
+    pub deletions: usize,
+    // old and new size in bytes when either side is binary
+    pub binary: Option<(usize, usize)>,
+}
+
+impl FileStat {
+    pub fn new(
+        path: String,
+        old: &[u8],
+        new: &[u8],
+        options: &DiffOptions,
+    ) -> Self {
+        let mut stat = Self {
+            path,
+            insertions: 0,
+            deletions: 0,
+            binary: None,
+        };
+        match diff_blobs(old, new, options) {
+            BlobDiff::Binary => stat.binary = Some((old.len(), new.len())),
+            BlobDiff::Text(hunks) => {
+                for line in hunks
+                    .iter()
+                    .flat_map(|hunk| &hunk.lines)
+                {
+                    match line.kind {
+                        LineKind::Insert => stat.insertions += 1,
+                        LineKind::Delete => stat.deletions += 1,
+                        LineKind::Context => {}
+                    }
+                }
+            }
+        }
+        stat
+    }
+}
+
+// the "diff --git" header git prints before each file's unified diff,
+// then the diff itself
+pub fn write_patch(
+    out: &mut dyn Write,
+    change: &FileChange,
+    old: &[u8],
+    new: &[u8],
+    options: &DiffOptions,
+) -> Result<()> {
+    writeln!(
+        out,
+        "diff --git a/{} b/{}",
+        change.old_path, change.new_path
+    )?;
+    let short = |hash: &Option<Hash>| match hash {
+        Some(hash) => hash.short(),
+        None => "0".repeat(7),
+    };
+    match change.change {
+        Change::Added => writeln!(out, "new file mode {}", FILE_MODE)?,
+        Change::Deleted => writeln!(out, "deleted file mode {}", FILE_MODE)?,
+        Change::Modified => {}
+        Change::Renamed | Change::Copied => {
+            let verb = match change.change {
+                Change::Renamed => "rename",
+                _ => "copy",
+            };
+            writeln!(
+                out,
+                "similarity index {}%",
+                change.similarity.unwrap_or_default()
+            )?;
+            writeln!(out, "{} from {}", verb, change.old_path)?;
+            writeln!(out, "{} to {}", verb, change.new_path)?;
+        }
+    }
+    let index = format!(
+        "index {}..{}",
+        short(&change.old_hash),
+        short(&change.new_hash)
+    );
+    match change.change {
+        // an exact rename or copy has no content change to show
+        _ if change.old_hash == change.new_hash => return Ok(()),
+        Change::Added | Change::Deleted => writeln!(out, "{}", index)?,
+        _ => writeln!(out, "{} {}", index, FILE_MODE)?,
+    }
+
+    let old_label = match change.old_hash {
+        Some(_) => format!("a/{}", change.old_path),
+        None => "/dev/null".to_string(),
+    };
+    let new_label = match change.new_hash {
+        Some(_) => format!("b/{}", change.new_path),
+        None => "/dev/null".to_string(),
+    };
+    write_unified(out, &old_label, &new_label, &diff_blobs(old, new, options))
+}
+
+// "M\tpath", or for a rename or copy the similarity and both paths, e.g.
+// "R086\told\tnew", as `--name-status` prints
+pub fn name_status(change: &FileChange) -> String {
+    match change.similarity {
+        Some(similarity) => format!(
+            "{}{:03}\t{}\t{}",
+            change.change.code(),
+            similarity,
+            change.old_path,
+            change.new_path
+        ),
+        None => format!("{}\t{}", change.change.code(), change.path()),
+    }
+}
+
+// the path `--stat` shows for a change: a rename or copy as
+// "dir/{old => new}/file", keeping the directories both paths share
+pub fn stat_path(change: &FileChange) -> String {
+    if change.similarity.is_none() {
+        return change.path().to_string();
+    }
+    let (old, new) = (&change.old_path, &change.new_path);
+
+    // whole leading and trailing directories in common, not overlapping
+    let prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .enumerate()
+        .filter(|(_, (byte, _))| *byte == b'/')
+        .map(|(i, _)| i + 1)
+        .last()
+        .unwrap_or(0);
+    let room = old.len().min(new.len()) - prefix;
+    let suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(room)
+        .take_while(|(a, b)| a == b)
+        .enumerate()
+        .filter(|(_, (byte, _))| *byte == b'/')
+        .map(|(i, _)| i + 1)
+        .last()
+        .unwrap_or(0);
+
+    if prefix == 0 && suffix == 0 {
+        return format!("{} => {}", old, new);
+    }
+    format!(
+        "{}{{{} => {}}}{}",
+        &old[..prefix],
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+        &old[old.len() - suffix..]
+    )
+}
+
+// the mode every file is written with; g1t does not track others
+const FILE_MODE: &str = "100644";
+
+// one "path | count +++--" line per file and a summary, scaled like git to
+// fit 80 columns
+pub fn write_stat(out: &mut dyn Write, stats: &[FileStat]) -> Result<()> {
+    if stats.is_empty() {
+        return Ok(());
+    }
+
+    let name_width = stats
+        .iter()
+        .map(|stat| stat.path.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_total = stats
+        .iter()
+        .map(|stat| stat.insertions + stat.deletions)
+        .max()
+        .unwrap_or(0);
+    let count_width = max_total.to_string().len();
+    let bar_width = 80usize
+        .saturating_sub(name_width + count_width + 4)
+        .max(10);
+    let scale = |n: usize| {
+        if max_total <= bar_width || n == 0 {
+            n
+        } else {
+            (n * bar_width / max_total).max(1)
+        }
+    };
+
+    let (mut insertions, mut deletions) = (0, 0);
+    for stat in stats {
+        match stat.binary {
+            Some((old, new)) => writeln!(
+                out,
+                " {:<name_width$} | Bin {} -> {} bytes",
+                stat.path, old, new
+            )?,
+            None => {
+                let bar = "+".repeat(scale(stat.insertions))
+                    + &"-".repeat(scale(stat.deletions));
+                // nothing after the count of an unchanged rename
+                let gap = if bar.is_empty() { "" } else { " " };
+                writeln!(
+                    out,
+                    " {:<name_width$} | {:>count_width$}{}{}",
+                    stat.path,
+                    stat.insertions + stat.deletions,
+                    gap,
+                    bar
+                )?
+            }
+        }
+        insertions += stat.insertions;
+        deletions += stat.deletions;
+    }
+
+    let plural = |n: usize, one: &str, many: &str| {
+        format!("{} {}", n, if n == 1 { one } else { many })
+    };
+    let mut summary = plural(stats.len(), "file changed", "files changed");
+    if insertions > 0 {
+        summary += &format!(
+            ", {}",
+            plural(insertions, "insertion(+)", "insertions(+)")
+        );
+    }
+    if deletions > 0 {
+        summary +=
+            &format!(", {}", plural(deletions, "deletion(-)", "deletions(-)"));
+    }
+    writeln!(out, " {}", summary)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a small deterministic generator, so failures reproduce
+    fn sequence(seed: &mut u64, len: usize, alphabet: u64) -> Vec<u64> {
+        (0..len)
+            .map(|_| {
+                *seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (*seed >> 33) % alphabet
+            })
+            .collect()
+    }
+
+    fn lcs_len(old: &[u64], new: &[u64]) -> usize {
+        let mut row = vec![0; new.len() + 1];
+        for a in old {
+            let mut diagonal = 0;
+            for (j, b) in new.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = match a == b {
+                    true => diagonal + 1,
+                    false => row[j].max(above),
+                };
+                diagonal = above;
+            }
+        }
+        row[new.len()]
+    }
+
+    // the ops walk both sides in order, and equal items really are equal;
+    // returns the number of inserts and deletes
+    fn check_script(old: &[u64], new: &[u64], ops: &[DiffOp]) -> usize {
+        let (mut o, mut n, mut edits) = (0, 0, 0);
+        for op in ops {
+            match *op {
+                DiffOp::Equal(a, b) => {
+                    assert_eq!((a, b), (o, n));
+                    assert_eq!(old[a], new[b]);
+                    (o, n) = (o + 1, n + 1);
+                }
+                DiffOp::Delete(a) => {
+                    assert_eq!(a, o);
+                    (o, edits) = (o + 1, edits + 1);
+                }
+                DiffOp::Insert(b) => {
+                    assert_eq!(b, n);
+                    (n, edits) = (n + 1, edits + 1);
+                }
+            }
+        }
+        assert_eq!((o, n), (old.len(), new.len()));
+        edits
+    }
+
+    #[test]
+    fn myers_finds_a_shortest_edit_script() {
+        let mut seed = 1;
+        for round in 0..500 {
+            let old = sequence(&mut seed, round % 23, 2 + round as u64 % 5);
+            let new = sequence(&mut seed, round % 19, 2 + round as u64 % 5);
+            let ops = diff_lines(&old, &new, DiffAlgorithm::Myers);
+            let edits = check_script(&old, &new, &ops);
+            for run in ops.split(|op| matches!(op, DiffOp::Equal(..))) {
+                assert!(
+                    run.is_sorted_by_key(|op| matches!(op, DiffOp::Insert(_)))
+                );
+            }
+            let shortest = old.len() + new.len() - 2 * lcs_len(&old, &new);
+            assert_eq!(edits, shortest, "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn myers_handles_a_whole_rewrite() {
+        let old: Vec<u64> = (0..2_000).collect();
+        let new: Vec<u64> = (2_000..4_000).collect();
+        let ops = diff_lines(&old, &new, DiffAlgorithm::Myers);
+        assert_eq!(check_script(&old, &new, &ops), 4_000);
+    }
+
+    #[test]
+    fn patience_gives_a_valid_script() {
+        let mut seed = 7;
+        for round in 0..200 {
+            let old = sequence(&mut seed, round % 17, 3 + round as u64 % 7);
+            let new = sequence(&mut seed, round % 13, 3 + round as u64 % 7);
+            let ops = diff_lines(&old, &new, DiffAlgorithm::Patience);
+            check_script(&old, &new, &ops);
+        }
+    }
+
+    fn headers(old: &str, new: &str, context: usize) -> Vec<String> {
+        let (old, new) =
+            (split_lines(old.as_bytes()), split_lines(new.as_bytes()));
+        let ops = diff_lines(&old, &new, DiffAlgorithm::Myers);
+        hunks(&ops, &old, &new, context)
+            .iter()
+            .map(Hunk::header)
+            .collect()
+    }
+
+    #[test]
+    fn hunk_headers() {
+        assert_eq!(headers("a\nb\nc\n", "a\nB\nc\n", 3), ["@@ -1,3 +1,3 @@"]);
+        assert_eq!(headers("a\nb\nc\n", "a\nB\nc\n", 0), ["@@ -2 +2 @@"]);
+        assert_eq!(headers("", "a\n", 3), ["@@ -0,0 +1 @@"]);
+        assert_eq!(headers("a\nb\n", "b\n", 0), ["@@ -1 +0,0 @@"]);
+        assert!(headers("a\n", "a\n", 3).is_empty());
+
+        // changes far apart get a hunk each, close ones share one
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let far = "x\n2\n3\n4\n5\n6\n7\n8\ny\n";
+        assert_eq!(
+            headers(old, far, 1),
+            ["@@ -1,2 +1,2 @@", "@@ -8,2 +8,2 @@"]
+        );
+        assert_eq!(
+            headers(old, far, 3),
+            ["@@ -1,4 +1,4 @@", "@@ -6,4 +6,4 @@"]
+        );
+        assert_eq!(headers(old, far, 4), ["@@ -1,9 +1,9 @@"]);
+    }
+
+    #[test]
+    fn missing_final_newline_is_a_change() {
+        let BlobDiff::Text(hunks) =
+            diff_blobs(b"a\n", b"a", &DiffOptions::default())
+        else {
+            panic!("text diffs as text");
+        };
+        let lines = &hunks[0].lines;
+        assert_eq!(lines[0].kind, LineKind::Delete);
+        assert_eq!(
+            (lines[1].kind, lines[1].newline),
+            (LineKind::Insert, false)
+        );
+    }
+
+    #[test]
+    fn stat_lines_line_up() {
+        let options = DiffOptions::default();
+        let stats = [
+            // a rename without changes has no bar, nor a space for one
+            FileStat::new("a.txt => b.txt".into(), b"a\n", b"a\n", &options),
+            FileStat::new(
+                "dir/file".into(),
+                b"1\n2\n3\n",
+                b"1\n3\n4\n5\n",
+                &options,
+            ),
+            FileStat::new("img".into(), b"\0ab", b"\0abc", &options),
+        ];
+
+        let mut out = Vec::new();
+        write_stat(&mut out, &stats).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            " a.txt => b.txt | 0\n \
+             dir/file       | 3 ++-\n \
+             img            | Bin 3 -> 4 bytes\n \
+             3 files changed, 2 insertions(+), 1 deletion(-)\n"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use core::fmt;
+use std::{io, path::PathBuf};
+
+use vfs::VfsError;
+
+use crate::Hash;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Vfs(VfsError),
+    // an object that exists but cannot be decoded
+    CorruptObject(Hash),
+    // a repository file such as the index that cannot be parsed, and why
+    CorruptFile(String, String),
+    MissingObject(Hash),
+    NotARepository(PathBuf),
+    // init target that holds something other than a repository
+    NotEmpty(PathBuf),
+    // a worktree operation run in a repository without one
+    BareRepository,
+    OutsideRepository(PathBuf),
+    PathNotFound(String),
+    // a path no worktree file may have, such as one inside the mount
+    InvalidPath(String),
+    BadRevision(String),
+    InvalidRefName(String),
+    // a ref that could not be updated, and why
+    RefUpdate(String, String),
+    // the prefix and every object id it matches
+    AmbiguousRevision(String, Vec<Hash>),
+    Conflict(String),
+    // a branch to delete that is not merged anywhere
+    NotMerged(String),
+    // paths whose merge conflicts are not resolved yet
+    Unmerged(Vec<String>),
+    // the commits given share no history
+    NoMergeBase,
+    // a commit that is not reachable from another, in that order
+    NotAncestor(Hash, Hash),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Vfs(err) => write!(f, "{}", err),
+            Error::CorruptObject(hash) => {
+                write!(f, "object {} is corrupt", hash)
+            }
+            Error::CorruptFile(path, err) => {
+                write!(f, "{} is corrupt: {}", path, err)
+            }
+            Error::MissingObject(hash) => {
+                write!(f, "object {} is missing", hash)
+            }
+            Error::NotARepository(path) => write!(
+                f,
+                "not a g1t repository (or any of the parent directories): {}",
+                path.display()
+            ),
+            Error::NotEmpty(path) => write!(
+                f,
+                "{} already exists and is not an empty directory",
+                path.display()
+            ),
+            Error::BareRepository => {
+                write!(f, "this operation must be run in a work tree")
+            }
+            Error::OutsideRepository(path) => {
+                write!(f, "{} is outside repository", path.display())
+            }
+            Error::PathNotFound(path) => {
+                write!(f, "pathspec '{}' did not match any files", path)
+            }
+            Error::InvalidPath(path) => write!(f, "invalid path '{}'", path),
+            Error::BadRevision(rev) => write!(f, "bad revision '{}'", rev),
+            Error::InvalidRefName(name) => {
+                write!(f, "'{}' is not a valid ref name", name)
+            }
+            Error::RefUpdate(name, reason) => {
+                write!(f, "cannot update ref '{}': {}", name, reason)
+            }
+            Error::AmbiguousRevision(prefix, matches) => {
+                write!(f, "short object id {} is ambiguous:", prefix)?;
+                for hash in matches {
+                    write!(f, "\n    {}", hash)?;
+                }
+                Ok(())
+            }
+            Error::Conflict(message) => write!(f, "{}", message),
+            Error::NotMerged(name) => write!(
+                f,
+                "the branch '{}' is not fully merged; \
+                 delete it anyway with 'g1t branch -D {}'",
+                name, name
+            ),
+            Error::NoMergeBase => write!(f, "no merge base found"),
+            Error::NotAncestor(ancestor, commit) => write!(
+                f,
+                "{} is not an ancestor of {}",
+                ancestor.short(),
+                commit.short()
+            ),
+            Error::Unmerged(paths) => {
+                write!(f, "you have unmerged files:")?;
+                for path in paths {
+                    write!(f, "\n\t{}", path)?;
+                }
+                write!(
+                    f,
+                    "\nfix them up in the work tree, then use \
+                     'g1t add <file>' to mark their resolution"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Vfs(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<VfsError> for Error {
+    fn from(err: VfsError) -> Self {
+        Error::Vfs(err)
+    }
+}
@@ -2,8 +2,10 @@ use std::path::PathBuf;
 
 use vfs::FileSystem;
 
-use crate::Hash;
+use crate::{Hash, Result};
 
+// hash-keyed files laid out like git's loose objects: the first two hex
+// digits name a fan-out directory, the remaining 38 the file inside it
 #[derive(Debug)]
 pub struct FsMap {
     mount: PathBuf,
@@ -16,75 +18,96 @@ impl FsMap {
         }
     }
 
+    fn path(&self, key: &Hash) -> String {
+        let hex = key.to_hex();
+        let (dir_name, file_name) = hex.split_at(2);
+
+        self.mount
+            .join(dir_name)
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
     pub fn insert(
         &mut self,
         key: Hash,
-        value: impl Into<String>,
+        value: &[u8],
         fs: &mut Box<dyn FileSystem>,
-    ) {
-        let (dir, file) = key.0.split_at(1);
-        // let dir_name = String::from_utf8_lossy(dir).to_string();
-        // let file_name = String::from_utf8_lossy(file).to_string();
-        let dir_name = dir
-            .to_vec()
-            .into_iter()
-            .map(|b| b.to_string())
-            .collect::<String>();
-        let file_name = file
-            .to_vec()
-            .into_iter()
-            .map(|b| b.to_string())
-            .collect::<String>();
-
-        let path = self
-            .mount
-            .join(dir_name)
-            .join(file_name);
-
-        fs.create_dir(
-            self.mount
-                .join(path.parent().unwrap())
-                .to_str()
-                .unwrap(),
-        )
-        .unwrap();
-
-        let mut file = fs
-            .create_file(path.to_str().unwrap())
-            .unwrap();
-
-        file.write_all(value.into().as_bytes())
-            .unwrap();
+    ) -> Result<()> {
+        let path = self.path(&key);
+
+        let (dir, _) = path
+            .rsplit_once('/')
+            .unwrap_or(("", &path));
+        if !fs.exists(dir)? {
+            fs.create_dir(dir)?;
+        }
+
+        let mut file = fs.create_file(&path)?;
+        file.write_all(value)?;
+        Ok(())
+    }
+
+    pub fn contains(&self, key: &Hash, fs: &dyn FileSystem) -> Result<bool> {
+        Ok(fs.exists(&self.path(key))?)
     }
 
-    pub fn get(&self, key: Hash, fs: &mut impl FileSystem) -> Option<String> {
-        let (dir, file) = key.0.split_at(1);
-        // let dir_name = String::from_utf8_lossy(dir).to_string();
-        // let file_name = String::from_utf8_lossy(file).to_string();
-
-        let dir_name = dir
-            .to_vec()
-            .into_iter()
-            .map(|b| b.to_string())
-            .collect::<String>();
-        let file_name = file
-            .to_vec()
-            .into_iter()
-            .map(|b| b.to_string())
-            .collect::<String>();
-        let path = self
+    pub fn get(
+        &self,
+        key: Hash,
+        fs: &dyn FileSystem,
+    ) -> Result<Option<Vec<u8>>> {
+        let path = self.path(&key);
+        if !fs.exists(&path)? {
+            return Ok(None);
+        }
+
+        let mut file = fs.open_file(&path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        Ok(Some(content))
+    }
+
+    pub fn keys(&self, fs: &dyn FileSystem) -> Result<Vec<Hash>> {
+        let mut keys = Vec::new();
+        for dir_name in fs.read_dir(&self.mount.to_string_lossy())? {
+            if dir_name.len() == 2 {
+                keys.extend(self.keys_with_prefix(&dir_name, fs)?);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    // every key whose hex form starts with `prefix`, which must be at least
+    // two lowercase hex digits long
+    pub fn keys_with_prefix(
+        &self,
+        prefix: &str,
+        fs: &dyn FileSystem,
+    ) -> Result<Vec<Hash>> {
+        let Some((dir_name, rest)) = prefix.split_at_checked(2) else {
+            return Ok(Vec::new());
+        };
+
+        let dir = self
             .mount
             .join(dir_name)
-            .join(file_name);
-
-        let file = fs.open_file(path.to_str().unwrap());
-        if let Ok(mut file) = file {
-            let mut content = String::new();
-            file.read_to_string(&mut content)
-                .unwrap();
-            Some(content)
-        } else {
-            None
+            .to_string_lossy()
+            .into_owned();
+        if !fs.exists(&dir)? {
+            return Ok(Vec::new());
         }
+
+        let mut keys: Vec<Hash> = fs
+            .read_dir(&dir)?
+            .filter(|file_name| file_name.starts_with(rest))
+            .filter_map(|file_name| {
+                Hash::from_hex(&format!("{}{}", dir_name, file_name))
+            })
+            .collect();
+        keys.sort();
+        Ok(keys)
     }
 }
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+
+use crate::{Error, Hash, Object, Result, Storage};
+
+// the parents of the commit `hash`, in order
+pub fn parents(storage: &impl Storage, hash: &Hash) -> Result<Vec<Hash>> {
+    match storage.read_object(hash)? {
+        Object::Commit { parents, .. } => Ok(parents),
+        _ => Err(Error::BadRevision(hash.to_hex())),
+    }
+}
+
+// every commit reachable from `tips` through parent links, tips included
+pub fn ancestors(
+    storage: &impl Storage,
+    tips: &[Hash],
+) -> Result<HashSet<Hash>> {
+    let mut seen: HashSet<Hash> = tips.iter().cloned().collect();
+    let mut pending: Vec<Hash> = tips.to_vec();
+    while let Some(hash) = pending.pop() {
+        for parent in parents(storage, &hash)? {
+            if seen.insert(parent.clone()) {
+                pending.push(parent);
+            }
+        }
+    }
+    Ok(seen)
+}
+
+// whether `ancestor` is reachable from `commit`; a commit is its own
+// ancestor
+pub fn is_ancestor(
+    storage: &impl Storage,
+    ancestor: &Hash,
+    commit: &Hash,
+) -> Result<bool> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![commit.clone()];
+    while let Some(hash) = pending.pop() {
+        if hash == *ancestor {
+            return Ok(true);
+        }
+        if seen.insert(hash.clone()) {
+            pending.extend(parents(storage, &hash)?);
+        }
+    }
+    Ok(false)
+}
+
+// the best common ancestors of `a` and `b`: those reachable from both that
+// no other such commit descends from, newest first. Usually one; none when
+// the histories are unrelated.
+pub fn merge_bases(
+    storage: &impl Storage,
+    a: &Hash,
+    b: &Hash,
+) -> Result<Vec<Hash>> {
+    merge_bases_many(storage, a, std::slice::from_ref(b))
+}
+
+// the best common ancestors of `one` and a merge of all of `others`, as
+// `git merge-base A B C` computes them
+pub fn merge_bases_many(
+    storage: &impl Storage,
+    one: &Hash,
+    others: &[Hash],
+) -> Result<Vec<Hash>> {
+    let ours = ancestors(storage, std::slice::from_ref(one))?;
+    let theirs = ancestors(storage, others)?;
+    best_ancestors(
+        storage,
+        ours.intersection(&theirs)
+            .cloned()
+            .collect(),
+    )
+}
+
+// the best ancestors common to every one of `commits`, for merging them
+// all at once
+pub fn octopus_merge_bases(
+    storage: &impl Storage,
+    commits: &[Hash],
+) -> Result<Vec<Hash>> {
+    let Some((first, rest)) = commits.split_first() else {
+        return Ok(Vec::new());
+    };
+
+    let mut bases = vec![first.clone()];
+    for commit in rest {
+        let mut next = HashSet::new();
+        for base in &bases {
+            next.extend(merge_bases(storage, base, commit)?);
+        }
+        bases = best_ancestors(storage, next)?;
+    }
+    Ok(bases)
+}
+
+// those of `candidates` that are not an ancestor of another one, newest
+// first
+fn best_ancestors(
+    storage: &impl Storage,
+    candidates: HashSet<Hash>,
+) -> Result<Vec<Hash>> {
+    let mut pending = Vec::new();
+    for hash in &candidates {
+        pending.extend(parents(storage, hash)?);
+    }
+    let redundant = ancestors(storage, &pending)?;
+
+    let mut bases = Vec::new();
+    for hash in candidates {
+        if !redundant.contains(&hash) {
+            let time = match storage.read_object(&hash)? {
+                Object::Commit { committer, .. } => committer.time,
+                _ => return Err(Error::BadRevision(hash.to_hex())),
+            };
+            bases.push((time, hash));
+        }
+    }
+    bases.sort_by(|a, b| b.cmp(a));
+    Ok(bases
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect())
+}
+
+// how many commits `commit` has that `base` lacks, and the reverse
+pub fn ahead_behind(
+    storage: &impl Storage,
+    commit: &Hash,
+    base: &Hash,
+) -> Result<(usize, usize)> {
+    let ours = ancestors(storage, std::slice::from_ref(commit))?;
+    let theirs = ancestors(storage, std::slice::from_ref(base))?;
+    Ok((
+        ours.difference(&theirs).count(),
+        theirs.difference(&ours).count(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JsonStorage, Signature};
+
+    // root - a1 - x - a2 and root - b1 - y - b2, where x and y each merge
+    // a1 and b1 (a criss-cross), plus c1 on root and an unrelated root u.
+    // Each commit is newer than the ones before it here.
+    struct Repo {
+        storage: JsonStorage,
+        commits: Vec<(&'static str, Hash)>,
+    }
+
+    fn repo() -> Repo {
+        let mut repo = Repo {
+            storage: JsonStorage::new(),
+            commits: Vec::new(),
+        };
+        let tree = repo
+            .storage
+            .hash_object(Object::tree(Vec::new()))
+            .unwrap();
+        let history: [(&str, &[&str]); 9] = [
+            ("root", &[]),
+            ("a1", &["root"]),
+            ("b1", &["root"]),
+            ("x", &["a1", "b1"]),
+            ("y", &["b1", "a1"]),
+            ("a2", &["x"]),
+            ("b2", &["y"]),
+            ("c1", &["root"]),
+            ("u", &[]),
+        ];
+        for (time, (name, parents)) in history.into_iter().enumerate() {
+            let sig = Signature::new("A".into(), "a@b".into(), time as i64, 0);
+            let commit = Object::commit(
+                name.to_string(),
+                tree.clone(),
+                repo.hashes(parents),
+                sig.clone(),
+                sig,
+            );
+            let hash = repo
+                .storage
+                .hash_object(commit)
+                .unwrap();
+            repo.commits.push((name, hash));
+        }
+        repo
+    }
+
+    impl Repo {
+        fn hash(&self, name: &str) -> Hash {
+            self.commits
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, hash)| hash.clone())
+                .unwrap()
+        }
+
+        fn hashes(&self, names: &[&str]) -> Vec<Hash> {
+            names
+                .iter()
+                .map(|name| self.hash(name))
+                .collect()
+        }
+
+        fn names(&self, hashes: Vec<Hash>) -> Vec<&'static str> {
+            hashes
+                .into_iter()
+                .map(|hash| {
+                    self.commits
+                        .iter()
+                        .find(|(_, h)| *h == hash)
+                        .map(|(name, _)| *name)
+                        .unwrap()
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn a_commit_is_its_own_ancestor() {
+        let repo = repo();
+        let is_ancestor = |ancestor: &str, commit: &str| {
+            is_ancestor(&repo.storage, &repo.hash(ancestor), &repo.hash(commit))
+                .unwrap()
+        };
+        assert!(is_ancestor("root", "a2"));
+        assert!(is_ancestor("b1", "a2"));
+        assert!(is_ancestor("a2", "a2"));
+        assert!(!is_ancestor("a2", "root"));
+        assert!(!is_ancestor("b2", "a2"));
+        assert!(!is_ancestor("u", "a2"));
+    }
+
+    #[test]
+    fn merge_bases_are_the_best_common_ancestors() {
+        let repo = repo();
+        let bases = |a: &str, b: &str| {
+            repo.names(
+                merge_bases(&repo.storage, &repo.hash(a), &repo.hash(b))
+                    .unwrap(),
+            )
+        };
+        // the criss-cross leaves two, newest first
+        assert_eq!(bases("a2", "b2"), ["b1", "a1"]);
+        assert_eq!(bases("a2", "c1"), ["root"]);
+        assert_eq!(bases("a1", "a2"), ["a1"]);
+        assert!(bases("a2", "u").is_empty());
+    }
+
+    #[test]
+    fn many_merges_the_others_but_octopus_takes_them_all() {
+        let repo = repo();
+        let many = |one: &str, others: &[&str]| {
+            let others = repo.hashes(others);
+            repo.names(
+                merge_bases_many(&repo.storage, &repo.hash(one), &others)
+                    .unwrap(),
+            )
+        };
+        let octopus = |commits: &[&str]| {
+            let commits = repo.hashes(commits);
+            repo.names(octopus_merge_bases(&repo.storage, &commits).unwrap())
+        };
+
+        // a1 is common to a2 and a merge of c1 and a1, but not to c1
+        assert_eq!(many("a2", &["c1", "a1"]), ["a1"]);
+        assert_eq!(octopus(&["a2", "c1", "a1"]), ["root"]);
+
+        assert_eq!(many("a2", &["b2", "c1"]), ["b1", "a1"]);
+        assert_eq!(octopus(&["a2", "b2"]), ["b1", "a1"]);
+        assert_eq!(octopus(&["a2"]), ["a2"]);
+        assert!(octopus(&["a2", "u"]).is_empty());
+        assert!(octopus(&[]).is_empty());
+    }
+
+    #[test]
+    fn ahead_behind_counts_each_side() {
+        let repo = repo();
+        let counts = |commit: &str, base: &str| {
+            ahead_behind(&repo.storage, &repo.hash(commit), &repo.hash(base))
+                .unwrap()
+        };
+        assert_eq!(counts("a2", "b2"), (2, 2));
+        assert_eq!(counts("a2", "root"), (4, 0));
+        assert_eq!(counts("root", "a2"), (0, 4));
+        assert_eq!(counts("a2", "u"), (5, 1));
+        assert_eq!(counts("a2", "a2"), (0, 0));
+    }
+}
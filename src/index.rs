@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{BlobHash, Hash};
+
+pub struct Content {
+    pub(crate) file_name: String,
+    pub(crate) content: Vec<u8>,
+}
+
+impl Content {
+    pub fn new(file_name: String, content: Vec<u8>) -> Self {
+        Self { file_name, content }
+    }
+}
+
+// staged files, kept sorted by path and stage with at most one entry per
+// path and stage
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(from = "IndexFile")]
+pub struct Index {
+    entries: Vec<Entry>,
+}
+
+// the on-disk form, which older versions could leave with duplicate paths
+#[derive(Deserialize)]
+struct IndexFile {
+    entries: Vec<Entry>,
+}
+
+impl From<IndexFile> for Index {
+    // the last entry for a path wins, as it was the last one added
+    fn from(file: IndexFile) -> Self {
+        let mut index = Index::new();
+        for entry in file.entries {
+            index.insert(entry);
+        }
+        index
+    }
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    // sorted by path, then stage
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    fn position(
+        &self,
+        file_name: &str,
+        stage: u8,
+    ) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|entry| {
+            (entry.file_name.as_str(), entry.stage).cmp(&(file_name, stage))
+        })
+    }
+
+    // the merged (stage 0) entry for `file_name`
+    pub fn get(&self, file_name: &str) -> Option<&Entry> {
+        self.position(file_name, 0)
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    // add `entry`, returning the one it replaced for the same path and
+    // stage. A merged entry resolves the path, dropping its other stages.
+    pub fn insert(&mut self, entry: Entry) -> Option<Entry> {
+        if entry.stage == 0 {
+            self.entries.retain(|other| {
+                other.file_name != entry.file_name || other.stage == 0
+            });
+        }
+        match self.position(&entry.file_name, entry.stage) {
+            Ok(i) => Some(std::mem::replace(&mut self.entries[i], entry)),
+            Err(i) => {
+                self.entries.insert(i, entry);
+                None
+            }
+        }
+    }
+
+    // the paths a merge left conflicted, with their versions by stage
+    pub fn unmerged(&self) -> Vec<Unmerged> {
+        let mut unmerged: Vec<Unmerged> = Vec::new();
+        for entry in self
+            .entries
+            .iter()
+            .filter(|entry| entry.stage > 0)
+        {
+            if unmerged
+                .last()
+                .is_none_or(|last| last.path != entry.file_name)
+            {
+                unmerged.push(Unmerged {
+                    path: entry.file_name.clone(),
+                    base: None,
+                    ours: None,
+                    theirs: None,
+                });
+            }
+            let last = unmerged
+                .last_mut()
+                .expect("pushed above");
+            let hash = Some(entry.blob_hash().clone());
+            match entry.stage {
+                1 => last.base = hash,
+                2 => last.ours = hash,
+                _ => last.theirs = hash,
+            }
+        }
+        unmerged
+    }
+
+    pub fn has_unmerged(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.stage > 0)
+    }
+
+    // drop every stage of `file_name`, returning the merged entry
+    pub fn remove(&mut self, file_name: &str) -> Option<Entry> {
+        let merged = self
+            .position(file_name, 0)
+            .ok()
+            .map(|i| self.entries.remove(i));
+        self.entries
+            .retain(|entry| entry.file_name != file_name);
+        merged
+    }
+}
+
+// a path with conflict stages, each version None when that side has no
+// such file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Unmerged {
+    pub path: String,
+    pub base: Option<Hash>,
+    pub ours: Option<Hash>,
+    pub theirs: Option<Hash>,
+}
+
+impl Unmerged {
+    // the two-letter code porcelain status uses, e.g. "UU" when both sides
+    // modified the file
+    pub fn code(&self) -> [char; 2] {
+        match (&self.base, &self.ours, &self.theirs) {
+            (Some(_), Some(_), Some(_)) => ['U', 'U'],
+            (None, Some(_), Some(_)) => ['A', 'A'],
+            (Some(_), None, Some(_)) => ['D', 'U'],
+            (Some(_), Some(_), None) => ['U', 'D'],
+            (None, Some(_), None) => ['A', 'U'],
+            (None, None, Some(_)) => ['U', 'A'],
+            _ => ['D', 'D'],
+        }
+    }
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self.code() {
+            ['U', 'U'] => "both modified:",
+            ['A', 'A'] => "both added:",
+            ['D', 'U'] => "deleted by us:",
+            ['U', 'D'] => "deleted by them:",
+            ['A', 'U'] => "added by us:",
+            ['U', 'A'] => "added by them:",
+            _ => "both deleted:",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    file_name: String,
+    blob_hash: BlobHash,
+    // 0 once merged; during a conflicted merge 1 holds the merge base's
+    // version, 2 ours and 3 theirs
+    #[serde(default, skip_serializing_if = "is_merged")]
+    stage: u8,
+}
+
+fn is_merged(stage: &u8) -> bool {
+    *stage == 0
+}
+
+impl Entry {
+    pub fn new(file_name: String, blob_hash: Hash) -> Self {
+        Self::at_stage(file_name, blob_hash, 0)
+    }
+
+    pub fn at_stage(file_name: String, blob_hash: Hash, stage: u8) -> Self {
+        Self {
+            file_name,
+            blob_hash: BlobHash(blob_hash),
+            stage,
+        }
+    }
+
+    // path relative to the worktree root, e.g. "dir/file"
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn blob_hash(&self) -> &Hash {
+        &self.blob_hash.0
+    }
+
+    pub fn stage(&self) -> u8 {
+        self.stage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Object;
+
+    fn blob(content: &str) -> Hash {
+        Object::blob(content.as_bytes().to_vec()).hash()
+    }
+
+    fn stages(index: &Index) -> Vec<(&str, u8)> {
+        index
+            .entries()
+            .iter()
+            .map(|entry| (entry.file_name(), entry.stage()))
+            .collect()
+    }
+
+    fn conflicted() -> Index {
+        let mut index = Index::new();
+        index.insert(Entry::new("z".to_string(), blob("z")));
+        for stage in [3, 1, 2] {
+            let content = stage.to_string();
+            index.insert(Entry::at_stage(
+                "a".to_string(),
+                blob(&content),
+                stage,
+            ));
+        }
+        index
+    }
+
+    #[test]
+    fn entries_stay_sorted_by_path_and_stage() {
+        let index = conflicted();
+        assert_eq!(stages(&index), [("a", 1), ("a", 2), ("a", 3), ("z", 0)]);
+        assert!(index.has_unmerged());
+        assert!(index.get("a").is_none());
+    }
+
+    #[test]
+    fn a_merged_entry_collapses_the_stages() {
+        let mut index = conflicted();
+        assert!(
+            index
+                .insert(Entry::new("a".to_string(), blob("resolved")))
+                .is_none()
+        );
+        assert_eq!(stages(&index), [("a", 0), ("z", 0)]);
+        assert!(!index.has_unmerged());
+        assert_eq!(index.get("a").unwrap().blob_hash(), &blob("resolved"));
+    }
+
+    #[test]
+    fn a_stage_replaces_only_its_own() {
+        let mut index = conflicted();
+        let old =
+            index.insert(Entry::at_stage("a".to_string(), blob("new"), 2));
+        assert_eq!(old.unwrap().blob_hash(), &blob("2"));
+        assert_eq!(stages(&index), [("a", 1), ("a", 2), ("a", 3), ("z", 0)]);
+    }
+
+    #[test]
+    fn unmerged_groups_the_stages_by_path() {
+        let mut index = conflicted();
+        index.insert(Entry::at_stage("b".to_string(), blob("b"), 3));
+        let unmerged = index.unmerged();
+        assert_eq!(unmerged.len(), 2);
+        assert_eq!(unmerged[0].code(), ['U', 'U']);
+        assert_eq!(unmerged[0].ours, Some(blob("2")));
+        assert_eq!(unmerged[1].code(), ['U', 'A']);
+    }
+
+    #[test]
+    fn remove_drops_every_stage() {
+        let mut index = conflicted();
+        assert!(index.remove("a").is_none());
+        assert_eq!(stages(&index), [("z", 0)]);
+    }
+}
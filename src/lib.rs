@@ -1,8 +1,47 @@
+pub mod error;
+pub use error::*;
+
 pub mod storage;
 pub use storage::*;
 
+pub mod object;
+pub use object::*;
+
+pub mod index;
+pub use index::*;
+
 pub mod runner;
 pub use runner::*;
 
 pub mod fsmap;
 pub use fsmap::*;
+
+pub mod config;
+pub use config::*;
+
+pub mod status;
+pub use status::*;
+
+pub mod refs;
+pub use refs::*;
+
+pub mod graph;
+pub use graph::*;
+
+pub mod checkout;
+pub use checkout::*;
+
+pub mod log;
+pub use log::*;
+
+pub mod diff;
+pub use diff::*;
+
+pub mod changes;
+pub use changes::*;
+
+pub mod merge;
+pub use merge::*;
+
+pub mod revision;
+pub use revision::*;
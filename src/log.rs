@@ -0,0 +1,574 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Hash, Object, RenameOptions, Result, Storage};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogOrder {
+    // newest committer date first, as commits are reached
+    #[default]
+    Default,
+    // newest first, but never a parent before all of its children
+    Date,
+    // children before parents, keeping each line of history together
+    Topo,
+}
+
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    // revisions to start from, HEAD when empty
+    pub revs: Vec<String>,
+    pub oneline: bool,
+    pub max_count: Option<usize>,
+    // committer date bounds in unix seconds, both inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    // only commits whose "Name <email>" author contains this
+    pub author: Option<String>,
+    pub graph: bool,
+    pub order: LogOrder,
+    // only commits changing this path, followed back across renames
+    pub follow: Option<String>,
+    // list each commit's changed files against its first parent
+    pub name_status: bool,
+    pub renames: RenameOptions,
+}
+
+impl LogOptions {
+    pub fn matches(&self, commit: &Object) -> bool {
+        let Object::Commit {
+            author, committer, ..
+        } = commit
+        else {
+            return false;
+        };
+
+        self.since
+            .is_none_or(|since| committer.time >= since)
+            && self
+                .until
+                .is_none_or(|until| committer.time <= until)
+            && self
+                .author
+                .as_ref()
+                .is_none_or(|pattern| {
+                    format!("{} <{}>", author.name, author.email)
+                        .contains(pattern)
+                })
+    }
+}
+
+fn commit_time(commit: &Object) -> i64 {
+    match commit {
+        Object::Commit { committer, .. } => committer.time,
+        _ => i64::MIN,
+    }
+}
+
+fn commit_parents(commit: &Object) -> &[Hash] {
+    match commit {
+        Object::Commit { parents, .. } => parents,
+        _ => &[],
+    }
+}
+
+// every commit reachable from `tips`, each once, in `order`
+pub fn history(
+    storage: &impl Storage,
+    tips: &[Hash],
+    order: LogOrder,
+) -> Result<Vec<Object>> {
+    let mut commits = HashMap::new();
+    let mut pending = tips.to_vec();
+    while let Some(hash) = pending.pop() {
+        if commits.contains_key(&hash) {
+            continue;
+        }
+        let commit = storage.read_object(&hash)?;
+        pending.extend(commit_parents(&commit).iter().cloned());
+        commits.insert(hash, commit);
+    }
+
+    let mut tips: Vec<&Hash> = tips.iter().collect();
+    let mut seen = HashSet::new();
+    tips.retain(|tip| seen.insert(*tip));
+
+    let mut sorted = Vec::with_capacity(commits.len());
+    if order == LogOrder::Default {
+        // newest first across every parent line
+        let mut seen: HashSet<&Hash> = tips.iter().copied().collect();
+        let mut pending = tips;
+        while let Some(newest) = pending
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, hash)| commit_time(&commits[**hash]))
+            .map(|(i, _)| i)
+        {
+            let commit = &commits[pending.swap_remove(newest)];
+            for parent in commit_parents(commit) {
+                if seen.insert(parent) {
+                    pending.push(parent);
+                }
+            }
+            sorted.push(commit.clone());
+        }
+        return Ok(sorted);
+    }
+
+    // a commit becomes ready once every child of it has been emitted
+    let mut children: HashMap<&Hash, usize> = HashMap::new();
+    for commit in commits.values() {
+        for parent in commit_parents(commit) {
+            *children.entry(parent).or_default() += 1;
+        }
+    }
+
+    let mut ready: Vec<&Hash> = tips
+        .into_iter()
+        .rev()
+        .filter(|tip| !children.contains_key(tip))
+        .collect();
+    while !ready.is_empty() {
+        let next = match order {
+            LogOrder::Topo => ready.len() - 1,
+            _ => ready
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, hash)| (commit_time(&commits[**hash]), *i))
+                .map(|(i, _)| i)
+                .unwrap_or_default(),
+        };
+        let commit = &commits[ready.remove(next)];
+
+        // reversed so the first parent is on top of the stack
+        for parent in commit_parents(commit).iter().rev() {
+            let count = children
+                .get_mut(parent)
+                .expect("counted above");
+            *count -= 1;
+            if *count == 0 {
+                ready.push(parent);
+            }
+        }
+        sorted.push(commit.clone());
+    }
+    Ok(sorted)
+}
+
+// the parents of each commit in `shown` rewritten to its nearest ancestors
+// that are also shown, so a graph of only those commits stays connected.
+// `commits` holds every commit between them, e.g. from `history`.
+pub fn shown_parents(
+    commits: &[Object],
+    shown: &HashSet<Hash>,
+) -> HashMap<Hash, Vec<Hash>> {
+    let parents: HashMap<Hash, &[Hash]> = commits
+        .iter()
+        .map(|commit| (commit.hash(), commit_parents(commit)))
+        .collect();
+
+    // the shown commits standing in for each hidden one
+    let mut nearest: HashMap<&Hash, Vec<Hash>> = HashMap::new();
+    let mut pending: Vec<(&Hash, bool)> = parents
+        .values()
+        .flat_map(|parents| parents.iter())
+        .filter(|parent| !shown.contains(*parent))
+        .map(|parent| (parent, false))
+        .collect();
+    while let Some((hash, expanded)) = pending.pop() {
+        if nearest.contains_key(hash) {
+            continue;
+        }
+        let hidden = |parent: &&Hash| !shown.contains(*parent);
+        let above = parents
+            .get(hash)
+            .copied()
+            .unwrap_or_default();
+        if !expanded {
+            pending.push((hash, true));
+            pending.extend(
+                above
+                    .iter()
+                    .filter(hidden)
+                    .filter(|parent| !nearest.contains_key(parent))
+                    .map(|parent| (parent, false)),
+            );
+            continue;
+        }
+        let found = stand_ins(above, shown, &nearest);
+        nearest.insert(hash, found);
+    }
+
+    shown
+        .iter()
+        .map(|hash| {
+            let above = parents
+                .get(hash)
+                .copied()
+                .unwrap_or_default();
+            (hash.clone(), stand_ins(above, shown, &nearest))
+        })
+        .collect()
+}
+
+// `parents` with each hidden one replaced by its `nearest` shown ancestors
+fn stand_ins(
+    parents: &[Hash],
+    shown: &HashSet<Hash>,
+    nearest: &HashMap<&Hash, Vec<Hash>>,
+) -> Vec<Hash> {
+    let mut found: Vec<Hash> = Vec::new();
+    for parent in parents {
+        let stand_ins = match shown.contains(parent) {
+            true => std::slice::from_ref(parent),
+            false => nearest
+                .get(parent)
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        };
+        for stand_in in stand_ins {
+            if !found.contains(stand_in) {
+                found.push(stand_in.clone());
+            }
+        }
+    }
+    found
+}
+
+// the lines `log` prints for `commit`, without a trailing newline each
+pub fn format_commit(commit: &Object, oneline: bool) -> Vec<String> {
+    let Object::Commit {
+        hash,
+        message,
+        parents,
+        author,
+        ..
+    } = commit
+    else {
+        return Vec::new();
+    };
+
+    if oneline {
+        let subject = message.lines().next().unwrap_or("");
+        return vec![format!("{} {}", hash.short(), subject)];
+    }
+
+    let mut lines = vec![format!("commit {}", hash)];
+    if parents.len() > 1 {
+        let parents: Vec<String> = parents
+            .iter()
+            .map(Hash::short)
+            .collect();
+        lines.push(format!("Merge: {}", parents.join(" ")));
+    }
+    lines.push(format!("Author: {} <{}>", author.name, author.email));
+    lines.push(format!("Date:   {}", author.format_date()));
+    lines.push(String::new());
+    for line in message.lines() {
+        lines.push(format!("    {}", line));
+    }
+    lines.push(String::new());
+    lines
+}
+
+// ASCII history graph drawn a commit at a time, like `git log --graph`.
+// Each line of history owns a two-character column: its symbol and the
+// gap where lines branching or joining it are drawn.
+#[derive(Debug, Default)]
+pub struct Graph {
+    // the commit each column is waiting for
+    columns: Vec<Hash>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the prefixes for the text of the next commit, `hash`: one for its
+    // first line, one per line joining or splitting the columns after it,
+    // and one to repeat for its remaining lines
+    pub fn next(
+        &mut self,
+        hash: &Hash,
+        parents: &[Hash],
+    ) -> (String, Vec<String>, String) {
+        let col = match self
+            .columns
+            .iter()
+            .position(|c| c == hash)
+        {
+            Some(col) => col,
+            None => {
+                self.columns.push(hash.clone());
+                self.columns.len() - 1
+            }
+        };
+        let before = self.columns.len();
+
+        let mut star = vec![' '; before * 2];
+        for i in 0..before {
+            star[i * 2] = if i == col { '*' } else { '|' };
+        }
+
+        // parents without a column yet take over this one
+        let mut fresh: Vec<Hash> = Vec::new();
+        for parent in parents {
+            if !self.columns.contains(parent) && !fresh.contains(parent) {
+                fresh.push(parent.clone());
+            }
+        }
+        let first_parent_at = parents.first().and_then(|parent| {
+            self.columns
+                .iter()
+                .position(|c| c == parent)
+        });
+
+        let mut transitions = Vec::new();
+        match (first_parent_at, fresh.len()) {
+            (Some(at), 0) => {
+                // the line joins the one already waiting for its parent;
+                // the right one of the two moves under to the left one
+                let (keep, gone) = (col.min(at), col.max(at));
+                self.columns[keep] = parents[0].clone();
+                self.columns.remove(gone);
+                transitions.push(join_row(before, keep, gone));
+            }
+            (None, 0) => {
+                // a root commit, after which the lines to its right move in
+                self.columns.remove(col);
+                if col + 1 < before {
+                    let mut row = vec![' '; before * 2];
+                    for i in 0..col {
+                        row[i * 2] = '|';
+                    }
+                    for i in col + 1..before {
+                        row[i * 2 - 1] = '/';
+                    }
+                    transitions.push(row);
+                }
+            }
+            (_, added) => {
+                self.columns.remove(col);
+                for (i, parent) in fresh.into_iter().enumerate() {
+                    self.columns.insert(col + i, parent);
+                }
+
+                if added > 1 {
+                    // a merge opens a column per extra parent to its right
+                    let extra = added - 1;
+                    let mut row = vec![' '; self.columns.len() * 2];
+                    for i in 0..=col {
+                        row[i * 2] = '|';
+                    }
+                    for k in 1..=extra {
+                        row[(col + k) * 2 - 1] = '\\';
+                    }
+                    for i in col + 1..before {
+                        row[i * 2 + extra] = '\\';
+                    }
+                    transitions.push(row);
+                }
+            }
+        }
+        let after = self.columns.len();
+
+        let mut rest = vec![' '; after * 2];
+        for i in 0..after {
+            rest[i * 2] = '|';
+        }
+
+        let width = before.max(after) * 2;
+        let pad = |row: Vec<char>| {
+            let row: String = row.into_iter().collect();
+            format!("{:<width$}", row)
+        };
+        (
+            pad(star),
+            transitions
+                .into_iter()
+                .map(pad)
+                .collect(),
+            pad(rest),
+        )
+    }
+}
+
+// column `gone` running under the columns between into `keep`, with every
+// column right of it moving one to the left
+fn join_row(columns: usize, keep: usize, gone: usize) -> Vec<char> {
+    let mut row = vec![' '; columns * 2];
+    for i in 0..gone {
+        row[i * 2] = '|';
+    }
+    for i in keep..gone - 1 {
+        row[i * 2 + 1] = '_';
+    }
+    row[gone * 2 - 1] = '/';
+    for i in gone + 1..columns {
+        row[i * 2 - 1] = '/';
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+    use crate::{JsonStorage, Signature, ancestors};
+
+    // c1 - c2 - m on main, where m merges side (c1 - s1). Only s1 is by B.
+    struct Repo {
+        storage: JsonStorage,
+        c1: Hash,
+        c2: Hash,
+        s1: Hash,
+        m: Hash,
+    }
+
+    fn repo() -> Repo {
+        let mut storage = JsonStorage::new();
+        let tree = storage
+            .hash_object(Object::tree(Vec::new()))
+            .unwrap();
+
+        let mut time = 0;
+        let mut commit =
+            |storage: &mut JsonStorage, name: &str, parents: Vec<Hash>| {
+                time += 1;
+                let sig = Signature::new(name.into(), "a@b".into(), time, 0);
+                let commit = Object::commit(
+                    format!("c{}", time),
+                    tree.clone(),
+                    parents,
+                    sig.clone(),
+                    sig,
+                );
+                storage.hash_object(commit).unwrap()
+            };
+        let c1 = commit(&mut storage, "A", vec![]);
+        let c2 = commit(&mut storage, "A", vec![c1.clone()]);
+        let s1 = commit(&mut storage, "B", vec![c1.clone()]);
+        let m = commit(&mut storage, "A", vec![c2.clone(), s1.clone()]);
+        Repo {
+            storage,
+            c1,
+            c2,
+            s1,
+            m,
+        }
+    }
+
+    // the graph rows drawn for the commits from `tips`, less those reachable
+    // from `excludes` or not matching `options`, and the columns left open
+    fn draw(
+        repo: &Repo,
+        excludes: &[Hash],
+        options: &LogOptions,
+    ) -> (Vec<String>, usize) {
+        let commits =
+            history(&repo.storage, slice::from_ref(&repo.m), LogOrder::Default)
+                .unwrap();
+        let excluded = ancestors(&repo.storage, excludes).unwrap();
+        let shown: Vec<&Object> = commits
+            .iter()
+            .filter(|commit| !excluded.contains(&commit.hash()))
+            .filter(|commit| options.matches(commit))
+            .collect();
+        let parents = shown_parents(
+            &commits,
+            &shown
+                .iter()
+                .map(|commit| commit.hash())
+                .collect(),
+        );
+
+        let mut graph = Graph::new();
+        let mut rows = Vec::new();
+        for commit in shown {
+            let hash = commit.hash();
+            let (first, transitions, _) = graph.next(&hash, &parents[&hash]);
+            rows.extend(
+                std::iter::once(first)
+                    .chain(transitions)
+                    .map(|row| row.trim_end().to_string()),
+            );
+        }
+        (rows, graph.columns.len())
+    }
+
+    fn hashes(commits: &[Object]) -> Vec<Hash> {
+        commits
+            .iter()
+            .map(Object::hash)
+            .collect()
+    }
+
+    #[test]
+    fn history_orders_by_date_or_topology() {
+        let repo = repo();
+        let tips = [repo.m.clone()];
+        let newest_first = [&repo.m, &repo.s1, &repo.c2, &repo.c1];
+        for order in [LogOrder::Default, LogOrder::Date] {
+            let commits = history(&repo.storage, &tips, order).unwrap();
+            assert_eq!(hashes(&commits), newest_first.map(Hash::clone));
+        }
+
+        // the first parent's line is finished before the side one
+        let commits = history(&repo.storage, &tips, LogOrder::Topo).unwrap();
+        let topo = [&repo.m, &repo.c2, &repo.s1, &repo.c1];
+        assert_eq!(hashes(&commits), topo.map(Hash::clone));
+    }
+
+    #[test]
+    fn a_graph_of_everything_forks_and_joins() {
+        let repo = repo();
+        let (rows, open) = draw(&repo, &[], &LogOptions::default());
+        assert_eq!(rows, ["*", "|\\", "| *", "* |", "|/", "*"]);
+        assert_eq!(open, 0);
+    }
+
+    #[test]
+    fn a_range_drops_the_parents_it_excludes() {
+        // side..main: s1 and c1 are excluded, so m is drawn as a plain
+        // commit on top of c2
+        let repo = repo();
+        let (rows, open) =
+            draw(&repo, slice::from_ref(&repo.s1), &LogOptions::default());
+        assert_eq!(rows, ["*", "*"]);
+        assert_eq!(open, 0);
+    }
+
+    #[test]
+    fn a_filter_joins_commits_to_their_nearest_shown_ancestors() {
+        let repo = repo();
+        let options = LogOptions {
+            author: Some("A <".to_string()),
+            ..Default::default()
+        };
+        let commits =
+            history(&repo.storage, slice::from_ref(&repo.m), LogOrder::Default)
+                .unwrap();
+        let shown = [&repo.m, &repo.c2, &repo.c1]
+            .into_iter()
+            .cloned()
+            .collect();
+        let parents = shown_parents(&commits, &shown);
+        assert_eq!(parents[&repo.m], [repo.c2.clone(), repo.c1.clone()]);
+        assert_eq!(parents[&repo.c2], slice::from_ref(&repo.c1));
+        assert!(parents[&repo.c1].is_empty());
+
+        let (rows, open) = draw(&repo, &[], &options);
+        assert_eq!(rows, ["*", "|\\", "* |", "|/", "*"]);
+        assert_eq!(open, 0);
+
+        // a filter leaving a single commit draws no lines at all
+        let options = LogOptions {
+            since: Some(4),
+            ..Default::default()
+        };
+        let (rows, open) = draw(&repo, &[], &options);
+        assert_eq!(rows, ["*"]);
+        assert_eq!(open, 0);
+    }
+}
@@ -1,192 +1,619 @@
-use std::path::PathBuf;
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
 
-use clap::{Parser, Subcommand};
-use g1t::{FsMap, FsMapedJson, Hash, JsonStorage, Runner, runner::Content};
-use vfs::{FileSystem, MemoryFS, VfsFileType};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use g1t::{
+    BranchAction, Cmd, DiffAlgorithm, DiffFormat, DiffOptions, Error,
+    FsMapedJson, LogOptions, LogOrder, MOUNT, RenameOptions, Result, Runner,
+    StatusFormat, Storage, parse_user_date,
+};
+use vfs::PhysicalFS;
 
-#[derive(Debug)]
-enum Node {
-    File { name: String, content: String },
-    Dir { name: String, children: Vec<Node> },
+#[derive(Debug, Parser)]
+#[command(name = "g1t", version, about = "A tiny git")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
 
-#[derive(Debug)]
-pub struct FsBuilder {
-    nodes: Vec<Node>,
+// how `diff` and `log` pair up deleted and added files
+#[derive(Debug, Args)]
+struct RenameArgs {
+    /// Report renames of files at least N% similar (50 by default)
+    #[arg(
+        short = 'M',
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "50",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    find_renames: Option<u8>,
+    /// Also report files copied from changed ones, at least N% similar
+    #[arg(
+        short = 'C',
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "50",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    find_copies: Option<u8>,
+    /// Show renamed files as a deletion and an addition
+    #[arg(long, conflicts_with_all = ["find_renames", "find_copies"])]
+    no_renames: bool,
 }
 
-impl FsBuilder {
-    pub fn new() -> Self {
-        Self { nodes: Vec::new() }
-    }
-
-    pub fn touch(
-        &mut self,
-        name: impl Into<String>,
-        content: impl Into<String>,
-    ) -> &mut Self {
-        self.nodes.push(Node::File {
-            name: name.into(),
-            content: content.into(),
-        });
-        self
-    }
-
-    pub fn mkdir(
-        &mut self,
-        name: impl Into<String>,
-        f: impl FnOnce(&mut FsBuilder),
-    ) -> &mut Self {
-        let mut child = FsBuilder::new();
-        f(&mut child);
-        self.nodes.push(Node::Dir {
-            name: name.into(),
-            children: child.nodes,
-        });
-        self
+impl From<RenameArgs> for RenameOptions {
+    fn from(args: RenameArgs) -> Self {
+        let defaults = RenameOptions::default();
+        Self {
+            enabled: !args.no_renames,
+            threshold: args
+                .find_copies
+                .or(args.find_renames)
+                .unwrap_or(defaults.threshold),
+            copies: args.find_copies.is_some(),
+        }
     }
+}
 
-    pub fn execute(
-        &mut self,
-        mount: impl Into<PathBuf>,
-        fs: &mut impl FileSystem,
-    ) {
-        let mount = mount.into();
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create an empty repository, or reopen an existing one
+    Init {
+        /// Directory to create the repository in, the current one by default
+        directory: Option<PathBuf>,
+        /// Create a repository without a worktree
+        #[arg(long)]
+        bare: bool,
+        /// Name of the branch HEAD starts out on
+        #[arg(short = 'b', long, default_value = "main")]
+        initial_branch: String,
+    },
+    /// Stage file contents for the next commit
+    Add {
+        /// Files or directories; directories are added recursively
+        #[arg(required_unless_present = "all")]
+        paths: Vec<PathBuf>,
+        /// Stage every change in the worktree, deletions included
+        #[arg(short = 'A', long, conflicts_with = "paths")]
+        all: bool,
+    },
+    /// Record the staged contents as a new commit
+    Commit {
+        #[arg(short, long)]
+        message: String,
+    },
+    /// Show staged, unstaged and untracked files
+    Status {
+        /// One stable "XY path" line per file, for scripts
+        #[arg(long, conflicts_with = "json")]
+        porcelain: bool,
+        /// The full status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the commit history
+    Log {
+        /// Revisions to start from, HEAD by default; ^rev, A..B and A...B
+        /// leave out history
+        revs: Vec<String>,
+        /// One line per commit: abbreviated id and subject
+        #[arg(long)]
+        oneline: bool,
+        /// Show at most this many commits
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        /// Only commits made on or after this date, e.g. 2024-01-31
+        #[arg(long, alias = "after", value_parser = parse_date_arg)]
+        since: Option<i64>,
+        /// Only commits made on or before this date
+        #[arg(long, alias = "before", value_parser = parse_date_arg)]
+        until: Option<i64>,
+        /// Only commits whose author name or email contains this
+        #[arg(long)]
+        author: Option<String>,
+        /// Draw the history as ASCII art
+        #[arg(long)]
+        graph: bool,
+        /// Show children before parents, keeping lines of history together
+        #[arg(long, conflicts_with = "date_order")]
+        topo_order: bool,
+        /// Show children before parents, otherwise newest first
+        #[arg(long)]
+        date_order: bool,
+        /// Only commits changing this file, following it across renames
+        #[arg(long, value_name = "PATH")]
+        follow: Option<String>,
+        /// List the files each commit changed and how
+        #[arg(long)]
+        name_status: bool,
+        #[command(flatten)]
+        renames: RenameArgs,
+    },
+    /// Show a commit, or the file or directory a revision like HEAD:path
+    /// names
+    Show {
+        /// Any revision, HEAD when omitted
+        object: Option<String>,
+    },
+    /// List, create, delete or rename branches
+    Branch {
+        /// Branch to create, delete or rename (the old name when renaming
+        /// with two names given)
+        name: Option<String>,
+        /// Revision to start a new branch at, HEAD by default; the new name
+        /// when renaming
+        start: Option<String>,
+        /// Delete the branch, refusing if it is not merged
+        #[arg(short, long, requires = "name")]
+        delete: bool,
+        /// Delete the branch even if it is not merged
+        #[arg(short = 'D', requires = "name")]
+        force_delete: bool,
+        /// Rename a branch, the current one when only one name is given
+        #[arg(short = 'm', long = "move", requires = "name")]
+        rename: bool,
+        /// Move an existing branch, or delete an unmerged one
+        #[arg(short, long)]
+        force: bool,
+        /// Track another branch, shown as ahead/behind with -vv
+        #[arg(short = 'u', long, value_name = "UPSTREAM")]
+        set_upstream_to: Option<String>,
+        /// Stop tracking the upstream
+        #[arg(long)]
+        unset_upstream: bool,
+        /// Show tip commits; twice to also show upstreams
+        #[arg(short, long, action = ArgAction::Count)]
+        verbose: u8,
+    },
+    /// Switch to a branch or commit, updating the worktree and index
+    Checkout {
+        /// Branch to switch to, or any revision to detach HEAD at; HEAD
+        /// when creating a branch with -b
+        #[arg(required_unless_present = "new_branch")]
+        rev: Option<String>,
+        /// Create a branch of this name at the revision and switch to it
+        #[arg(short = 'b', value_name = "NEW_BRANCH")]
+        new_branch: Option<String>,
+        /// Detach HEAD even when the revision is a branch
+        #[arg(long)]
+        detach: bool,
+        /// Throw away local changes that are in the way
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Switch to a branch, updating the worktree and index
+    Switch {
+        /// Branch to switch to, or the start point with -c
+        branch: Option<String>,
+        /// Create a branch of this name and switch to it
+        #[arg(short = 'c', long = "create", value_name = "NEW_BRANCH")]
+        new_branch: Option<String>,
+        /// Detach HEAD at the revision instead
+        #[arg(long)]
+        detach: bool,
+        /// Throw away local changes that are in the way
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show changes between the worktree, the index and commits
+    Diff {
+        /// Compare against the worktree (one revision) or between two;
+        /// A..B is the same as A B and A...B compares B with the merge base
+        revs: Vec<String>,
+        /// Compare the index against HEAD, or against the one revision
+        #[arg(long, alias = "staged")]
+        cached: bool,
+        /// Summarize the changed lines per file
+        #[arg(long, group = "format")]
+        stat: bool,
+        /// Only the names of changed files
+        #[arg(long, group = "format")]
+        name_only: bool,
+        /// The names of changed files and how they changed
+        #[arg(long, group = "format")]
+        name_status: bool,
+        /// Lines of context around each change
+        #[arg(short = 'U', long = "unified", default_value_t = 3)]
+        context: usize,
+        /// Anchor on lines that occur once on each side
+        #[arg(long)]
+        patience: bool,
+        #[command(flatten)]
+        renames: RenameArgs,
+    },
+    /// Join another branch's history into the current one
+    Merge {
+        /// Branch or revision to merge
+        rev: String,
+        /// Message for the merge commit
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Create a merge commit even when a fast-forward would do
+        #[arg(long)]
+        no_ff: bool,
+    },
+    /// Find the best common ancestor of commits
+    MergeBase {
+        /// Commits to compare; the first against a merge of the rest
+        #[arg(required = true)]
+        commits: Vec<String>,
+        /// Print every best common ancestor, not just one
+        #[arg(short, long)]
+        all: bool,
+        /// Find the ancestors common to all commits, for an octopus merge
+        #[arg(long, conflicts_with = "is_ancestor")]
+        octopus: bool,
+        /// Exit with 0 if the first commit is an ancestor of the second,
+        /// 1 if not
+        #[arg(long, conflicts_with = "all")]
+        is_ancestor: bool,
+    },
+    /// Print the object ids revisions name, e.g. HEAD~2, main@{1},
+    /// HEAD:file or main..feature
+    RevParse {
+        #[arg(required = true)]
+        revs: Vec<String>,
+    },
+    /// Get or set a repository option such as user.name
+    Config { key: String, value: Option<String> },
+}
 
-        fs.create_dir(mount.to_str().unwrap())
-            .unwrap();
+// a directory laid out like a repository itself, with no worktree around it
+fn is_bare(dir: &Path) -> bool {
+    dir.join("HEAD").is_file()
+        && dir.join("objects").is_dir()
+        && dir.join("refs").is_dir()
+}
 
-        for node in self.nodes.iter() {
-            build_node(fs, &mount, node);
+// walk up from `dir` until a directory holding the mount, or a bare
+// repository, is found. Returns it and whether it is bare.
+fn discover(dir: &Path) -> Option<(PathBuf, bool)> {
+    dir.ancestors().find_map(|dir| {
+        if dir.join(MOUNT).is_dir() {
+            Some((dir.to_path_buf(), false))
+        } else if is_bare(dir) {
+            Some((dir.to_path_buf(), true))
+        } else {
+            None
         }
-    }
+    })
 }
 
-fn build_node(fs: &mut impl FileSystem, base: &PathBuf, node: &Node) {
-    match node {
-        Node::File { name, content } => {
-            let path = base.join(&name);
-            fs.create_file(path.to_str().unwrap())
-                .unwrap();
-            fs.append_file(path.to_str().unwrap())
-                .unwrap()
-                .write_all(content.as_bytes())
-                .unwrap();
-        }
-        Node::Dir { name, children } => {
-            let path = base.join(&name);
-            fs.create_dir(path.to_str().unwrap())
-                .unwrap();
-            for child in children {
-                build_node(fs, &path, child);
+fn parse_date_arg(date: &str) -> std::result::Result<i64, String> {
+    parse_user_date(date).ok_or_else(|| format!("invalid date '{}'", date))
+}
+
+// turn a path given on the command line into the "/a/b" form the
+// worktree filesystem, rooted at `root`, expects
+fn worktree_path(root: &Path, cwd: &Path, path: &Path) -> Option<String> {
+    let mut normalized = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
             }
+            Component::CurDir => {}
+            component => normalized.push(component),
         }
     }
-}
 
-fn main() {
-    // let mut fs_builder = FsBuilder::new();
-    // fs_builder
-    //     .mkdir("test_dir", |builder| {
-    //         builder.touch("test_file", "test_content");
-    //         builder.mkdir("test_dir2", |builder| {
-    //             builder.touch("test_file2", "test_content2");
-    //         });
-    //     })
-    //     .touch("test_file3", "test_content3");
-
-    // let mut fs = MemoryFS::new();
-    // fs_builder.execute("/root", &mut fs);
-
-    // let mut runner = Runner::new(
-    //     Box::new(JsonStorage::new(Box::new(MemoryFS::new()))),
-    //     Box::new(fs),
-    // );
-
-    // println!(
-    //     "{:?} \n{:?}",
-    //     runner.storage.index(),
-    //     runner.storage.objects()
-    // );
-
-    // runner.run(g1t::Cmd::Add {
-    //     file_name: "/root/test_dir/test_file".to_string(),
-    // });
-
-    // runner.run(g1t::Cmd::Commit {
-    //     message: "test commit".to_string(),
-    // });
-
-    // println!(
-    //     "== after add {:#?}\n{:#?}",
-    //     runner.storage.index(),
-    //     runner.storage.objects()
-    // );
-
-    let mut fs_builder = FsBuilder::new();
-    fs_builder.mkdir("g1t", |builder| {});
-
-    let mut fs = MemoryFS::new();
-    fs_builder.execute("/root", &mut fs);
-
-    let mut fs_maped_json = FsMapedJson::new("/root/g1t".into(), Box::new(fs));
-    println!("{:?}", fs_maped_json);
-
-    // fs_maped_json.update_index(Content::new(
-    //     "test_file".to_string(),
-    //     "test_content".to_string(),
-    // ));
-
-    let infs = MemoryFS::new();
-
-    let mut runner = Runner::new(fs_maped_json, Box::new(infs));
-
-    runner.run(g1t::Cmd::Add {
-        file_name: "/root/test_dir/test_file".to_string(),
-    });
-
-    // println!("{:?}", fs_maped_json);
-    // into_data(&mut fs_maped_json.fs, &PathBuf::from("/root/g1t"));
-    // let mut fs_map = FsMap::new("/root");
-    // fs_map.insert(Hash(vec![1, 2, 3, 4, 5]), "test_content", &mut fs);
-
-    // let mut entries = fs.read_dir("/root").unwrap();
-    // for entry in entries {
-    //     println!("{:?}", entry);
-    // }
+    let relative = normalized.strip_prefix(root).ok()?;
+    let components = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(format!("/{}", components.join("/")))
 }
 
-fn pretty(fs: Box<dyn FileSystem>) {
-    let mut entries = fs.read_dir("/test_dir").unwrap();
-    for entry in entries {
-        println!("{:?}", entry);
+// distinct exit status per kind of failure, so scripts can tell them apart
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Conflict(_)
+        | Error::NotMerged(_)
+        | Error::NoMergeBase
+        | Error::NotAncestor(..) => 1,
+        Error::CorruptObject(_)
+        | Error::CorruptFile(..)
+        | Error::MissingObject(_) => 65,
+        Error::PathNotFound(_) => 66,
+        Error::Io(_) | Error::Vfs(_) => 74,
+        Error::NotARepository(_)
+        | Error::NotEmpty(_)
+        | Error::BareRepository
+        | Error::OutsideRepository(_)
+        | Error::InvalidPath(_)
+        | Error::InvalidRefName(_)
+        | Error::RefUpdate(..)
+        | Error::Unmerged(_) => 128,
+        Error::BadRevision(_) | Error::AmbiguousRevision(..) => 129,
     }
 }
 
-fn into_data(fs: &mut Box<dyn FileSystem>, root: &PathBuf) {
-    let mut entries = fs
-        .read_dir(root.to_str().unwrap())
-        .unwrap();
-
-    for entry in entries {
-        let meta = fs
-            .metadata(
-                root.join(entry.clone())
-                    .to_str()
-                    .unwrap(),
-            )
-            .unwrap();
-
-        match meta.file_type {
-            VfsFileType::File => {
-                println!("file: {:#?}", entry);
-                println!("file: {:#?}", root.join(entry).to_str().unwrap());
+fn run(cli: Cli) -> Result<()> {
+    let cwd = std::env::current_dir()?.canonicalize()?;
+
+    if let Command::Init {
+        directory,
+        bare,
+        initial_branch,
+    } = cli.command
+    {
+        let dir = cwd.join(directory.unwrap_or_default());
+        std::fs::create_dir_all(&dir)?;
+        let dir = dir.canonicalize()?;
+
+        // a bare repository is the directory itself
+        let (root, mount) = if bare {
+            (dir.clone(), "/".to_string())
+        } else {
+            (dir.join(MOUNT), format!("/{}", MOUNT))
+        };
+
+        let fs = PhysicalFS::new(&dir);
+        let existed = FsMapedJson::is_repository(Path::new(&mount), &fs)?;
+        FsMapedJson::init(mount.into(), Box::new(fs), bare, &initial_branch)
+            .map_err(|err| match err {
+                Error::NotEmpty(_) => Error::NotEmpty(root.clone()),
+                err => err,
+            })?;
+
+        let verb = if existed {
+            "Reinitialized existing"
+        } else {
+            "Initialized empty"
+        };
+        println!("{} g1t repository in {}", verb, root.display());
+        return Ok(());
+    }
+
+    let (root, bare) =
+        discover(&cwd).ok_or_else(|| Error::NotARepository(cwd.clone()))?;
+
+    let mount = if bare {
+        "/".to_string()
+    } else {
+        format!("/{}", MOUNT)
+    };
+    let storage = FsMapedJson::on_disk(&root, mount.into())?;
+    let mut runner = if bare {
+        Runner::bare(storage)
+    } else {
+        Runner::new(storage, Box::new(PhysicalFS::new(&root)))
+    };
+
+    match cli.command {
+        Command::Init { .. } => unreachable!(),
+        Command::Add { all: true, .. } => runner.run(Cmd::AddAll),
+        Command::Add { paths, .. } => {
+            for path in paths {
+                let file_name = worktree_path(&root, &cwd, &path)
+                    .ok_or(Error::OutsideRepository(path))?;
+                runner.run(Cmd::Add { file_name })?;
             }
-            VfsFileType::Directory => {
-                println!("dir: {:#?}", entry);
-                into_data(fs, &PathBuf::from(root).join(entry.clone()));
-                println!("dir: {:#?}", root.join(entry).to_str().unwrap());
+            Ok(())
+        }
+        Command::Commit { message } => runner.run(Cmd::Commit { message }),
+        Command::Status { porcelain, json } => {
+            let format = if porcelain {
+                StatusFormat::Porcelain
+            } else if json {
+                StatusFormat::Json
+            } else {
+                StatusFormat::Long
+            };
+            runner.run(Cmd::Status { format })
+        }
+        Command::Log {
+            revs,
+            oneline,
+            max_count,
+            since,
+            until,
+            author,
+            graph,
+            topo_order,
+            date_order,
+            follow,
+            name_status,
+            renames,
+        } => {
+            // like git, the graph needs parents after their children
+            let order = if topo_order || (graph && !date_order) {
+                LogOrder::Topo
+            } else if date_order {
+                LogOrder::Date
+            } else {
+                LogOrder::Default
+            };
+            runner.run(Cmd::Log {
+                options: LogOptions {
+                    revs,
+                    oneline,
+                    max_count,
+                    since,
+                    until,
+                    author,
+                    graph,
+                    order,
+                    follow,
+                    name_status,
+                    renames: renames.into(),
+                },
+            })
+        }
+        Command::Show { object } => runner.run(Cmd::Show { object }),
+        Command::Branch {
+            name,
+            start,
+            delete,
+            force_delete,
+            rename,
+            force,
+            set_upstream_to,
+            unset_upstream,
+            verbose,
+        } => {
+            let action = if delete || force_delete {
+                BranchAction::Delete {
+                    name: name.ok_or(Error::BadRevision(String::new()))?,
+                    force: force || force_delete,
+                }
+            } else if rename {
+                let old = name.ok_or(Error::BadRevision(String::new()))?;
+                match start {
+                    Some(new) => BranchAction::Rename {
+                        old: Some(old),
+                        new,
+                    },
+                    None => BranchAction::Rename {
+                        old: None,
+                        new: old,
+                    },
+                }
+            } else if let Some(upstream) = set_upstream_to {
+                BranchAction::SetUpstream { name, upstream }
+            } else if unset_upstream {
+                BranchAction::UnsetUpstream { name }
+            } else if let Some(name) = name {
+                BranchAction::Create { name, start, force }
+            } else {
+                BranchAction::List { verbose }
+            };
+            runner.run(Cmd::Branch { action })
+        }
+        Command::Checkout {
+            rev,
+            new_branch,
+            detach,
+            force,
+        } => runner.run(Cmd::Checkout {
+            rev: rev.unwrap_or_else(|| "HEAD".to_string()),
+            new_branch,
+            detach,
+            force,
+        }),
+        Command::Switch {
+            branch,
+            new_branch,
+            detach,
+            force,
+        } => {
+            // unlike checkout, switch only takes a plain revision as the
+            // start point of a new branch or with --detach
+            let rev = match branch {
+                Some(branch) => {
+                    if new_branch.is_none() && !detach {
+                        let name = format!("refs/heads/{}", branch);
+                        if runner
+                            .storage
+                            .read_ref(&name)?
+                            .is_none()
+                        {
+                            return Err(Error::BadRevision(branch));
+                        }
+                    }
+                    branch
+                }
+                None if new_branch.is_some() => "HEAD".to_string(),
+                None => return Err(Error::BadRevision(String::new())),
+            };
+            runner.run(Cmd::Checkout {
+                rev,
+                new_branch,
+                detach,
+                force,
+            })
+        }
+        Command::Diff {
+            revs,
+            cached,
+            stat,
+            name_only,
+            name_status,
+            context,
+            patience,
+            renames,
+        } => {
+            let format = if stat {
+                DiffFormat::Stat
+            } else if name_only {
+                DiffFormat::NameOnly
+            } else if name_status {
+                DiffFormat::NameStatus
+            } else {
+                DiffFormat::Patch
+            };
+            let algorithm = if patience {
+                DiffAlgorithm::Patience
+            } else {
+                DiffAlgorithm::Myers
+            };
+            runner.run(Cmd::Diff {
+                cached,
+                revs,
+                format,
+                options: DiffOptions {
+                    context,
+                    algorithm,
+                    renames: renames.into(),
+                },
+            })
+        }
+        Command::Merge {
+            rev,
+            message,
+            no_ff,
+        } => runner.run(Cmd::Merge {
+            rev,
+            message,
+            no_ff,
+        }),
+        Command::MergeBase {
+            commits,
+            all,
+            octopus,
+            is_ancestor,
+        } => {
+            if !is_ancestor {
+                return runner.run(Cmd::MergeBase {
+                    commits,
+                    all,
+                    octopus,
+                });
             }
+            let [ancestor, commit] = <[String; 2]>::try_from(commits)
+                .map_err(|commits| Error::BadRevision(commits.join(" ")))?;
+            runner.run(Cmd::IsAncestor { ancestor, commit })
         }
+        Command::RevParse { revs } => runner.run(Cmd::RevParse { revs }),
+        Command::Config { key, value } => {
+            runner.run(Cmd::Config { key, value })
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if let Err(err) = run(cli) {
+        // a closed pipe (e.g. `g1t log | head`) is not worth reporting
+        if let Error::Io(io_err) = &err
+            && io_err.kind() == ErrorKind::BrokenPipe
+        {
+            return;
+        }
+        // like git, merge-base answers "no" with its exit status alone
+        if matches!(err, Error::NoMergeBase | Error::NotAncestor(..)) {
+            std::process::exit(exit_code(&err));
+        }
+
+        eprintln!("fatal: {}", err);
+        std::process::exit(exit_code(&err));
     }
 }
@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    DiffAlgorithm, DiffOp, Hash, Object, Result, Storage, blob_content,
+    diff_lines, is_binary, split_lines,
+};
+
+// one file merged line by line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMerge {
+    // the merged content, with conflict markers around each conflict
+    pub content: Vec<u8>,
+    pub conflicts: usize,
+}
+
+// for each line of `base`, the line of `other` it was kept as, if any
+fn matches(base: &[&[u8]], other: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; base.len()];
+    for op in diff_lines(base, other, DiffAlgorithm::Myers) {
+        if let DiffOp::Equal(o, n) = op {
+            matched[o] = Some(n);
+        }
+    }
+    matched
+}
+
+// merge the changes `ours` and `theirs` each made to `base`, like diff3:
+// runs of base lines both sides kept split the files into chunks, and a
+// chunk only one side changed takes that side. A chunk both changed
+// differently becomes a conflict, written between markers labelled with
+// `ours_label` and `theirs_label`. Binary files only merge when one side
+// left them alone.
+pub fn merge_blobs(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    ours_label: &str,
+    theirs_label: &str,
+) -> FileMerge {
+    if ours == theirs || base == theirs {
+        return FileMerge {
+            content: ours.to_vec(),
+            conflicts: 0,
+        };
+    }
+    if base == ours {
+        return FileMerge {
+            content: theirs.to_vec(),
+            conflicts: 0,
+        };
+    }
+    if is_binary(base) || is_binary(ours) || is_binary(theirs) {
+        return FileMerge {
+            content: ours.to_vec(),
+            conflicts: 1,
+        };
+    }
+
+    let (base, ours, theirs) =
+        (split_lines(base), split_lines(ours), split_lines(theirs));
+    let (in_ours, in_theirs) = (matches(&base, &ours), matches(&base, &theirs));
+
+    let mut merged = FileMerge {
+        content: Vec::new(),
+        conflicts: 0,
+    };
+    let (mut o, mut a, mut b) = (0, 0, 0);
+    loop {
+        // base lines both sides kept in place
+        let mut stable = 0;
+        while o + stable < base.len()
+            && in_ours[o + stable] == Some(a + stable)
+            && in_theirs[o + stable] == Some(b + stable)
+        {
+            stable += 1;
+        }
+        if stable > 0 {
+            for line in &base[o..o + stable] {
+                merged.content.extend_from_slice(line);
+            }
+            (o, a, b) = (o + stable, a + stable, b + stable);
+            continue;
+        }
+
+        // up to the next base line both sides kept, or the end
+        let next = (o..base.len())
+            .find(|&i| in_ours[i].is_some() && in_theirs[i].is_some());
+        let (o_end, a_end, b_end) = match next {
+            Some(i) => (i, in_ours[i].unwrap_or(a), in_theirs[i].unwrap_or(b)),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        let chunk = (&base[o..o_end], &ours[a..a_end], &theirs[b..b_end]);
+        merge_chunk(&mut merged, chunk, ours_label, theirs_label);
+
+        if next.is_none() {
+            break;
+        }
+        (o, a, b) = (o_end, a_end, b_end);
+    }
+    merged
+}
+
+// the base, our and their lines of one chunk
+type Chunk<'a, 'b> = (&'b [&'a [u8]], &'b [&'a [u8]], &'b [&'a [u8]]);
+
+fn merge_chunk(
+    merged: &mut FileMerge,
+    (base, ours, theirs): Chunk,
+    ours_label: &str,
+    theirs_label: &str,
+) {
+    let clean = if ours == theirs || base == theirs {
+        Some(ours)
+    } else if base == ours {
+        Some(theirs)
+    } else {
+        None
+    };
+
+    let content = &mut merged.content;
+    match clean {
+        Some(lines) => {
+            for line in lines {
+                content.extend_from_slice(line);
+            }
+        }
+        None => {
+            merged.conflicts += 1;
+            content.extend_from_slice(
+                format!("<<<<<<< {}\n", ours_label).as_bytes(),
+            );
+            push_side(content, ours);
+            content.extend_from_slice(b"=======\n");
+            push_side(content, theirs);
+            content.extend_from_slice(
+                format!(">>>>>>> {}\n", theirs_label).as_bytes(),
+            );
+        }
+    }
+}
+
+// one side of a conflict, ending in a newline so the marker after it starts
+// a line of its own
+fn push_side(content: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        content.extend_from_slice(line);
+    }
+    if lines
+        .last()
+        .is_some_and(|line| !line.ends_with(b"\n"))
+    {
+        content.push(b'\n');
+    }
+}
+
+// why a path could not be merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    // both sides changed the same lines
+    Content,
+    // both sides added the file, with different contents
+    AddAdd,
+    // one side changed the file the other deleted
+    ModifyDelete,
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeConflict {
+    pub path: String,
+    pub kind: ConflictKind,
+    // the three versions, None where the file is missing
+    pub base: Option<Hash>,
+    pub ours: Option<Hash>,
+    pub theirs: Option<Hash>,
+    // what the worktree gets: the file with conflict markers, or the
+    // version of the side that kept it
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct TreeMerge {
+    // the merged files; a conflicted path keeps our version, or theirs
+    // when we deleted it
+    pub files: BTreeMap<String, Hash>,
+    pub conflicts: Vec<MergeConflict>,
+    // paths both sides changed, merged line by line
+    pub merged: Vec<String>,
+}
+
+// merge the flat path-to-blob maps `ours` and `theirs` against `base`: a
+// path only one side changed takes that side, and one both changed is
+// merged line by line. Cleanly merged contents are stored as blobs.
+pub fn merge_trees(
+    storage: &mut impl Storage,
+    base: &BTreeMap<String, Hash>,
+    ours: &BTreeMap<String, Hash>,
+    theirs: &BTreeMap<String, Hash>,
+    ours_label: &str,
+    theirs_label: &str,
+) -> Result<TreeMerge> {
+    let mut result = TreeMerge::default();
+    let paths: BTreeSet<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let clean = if o == t || b == t {
+            Some(o)
+        } else if b == o {
+            Some(t)
+        } else {
+            None
+        };
+        if let Some(hash) = clean {
+            if let Some(hash) = hash {
+                result
+                    .files
+                    .insert(path.clone(), hash.clone());
+            }
+            continue;
+        }
+
+        let conflict = |kind, content| MergeConflict {
+            path: path.clone(),
+            kind,
+            base: b.cloned(),
+            ours: o.cloned(),
+            theirs: t.cloned(),
+            content,
+        };
+        match (o, t) {
+            (Some(o), Some(t)) => {
+                result.merged.push(path.clone());
+                let merged = merge_blobs(
+                    &blob_content(storage, b)?,
+                    &blob_content(storage, Some(o))?,
+                    &blob_content(storage, Some(t))?,
+                    ours_label,
+                    theirs_label,
+                );
+                if merged.conflicts == 0 {
+                    let hash =
+                        storage.hash_object(Object::blob(merged.content))?;
+                    result.files.insert(path.clone(), hash);
+                    continue;
+                }
+
+                let kind = match b {
+                    Some(_) => ConflictKind::Content,
+                    None => ConflictKind::AddAdd,
+                };
+                result
+                    .files
+                    .insert(path.clone(), o.clone());
+                result
+                    .conflicts
+                    .push(conflict(kind, merged.content));
+            }
+            (kept, _) => {
+                let Some(kept) = kept.or(t) else {
+                    continue;
+                };
+                result
+                    .files
+                    .insert(path.clone(), kept.clone());
+                let content = blob_content(storage, Some(kept))?;
+                result
+                    .conflicts
+                    .push(conflict(ConflictKind::ModifyDelete, content));
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JsonStorage;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let merged = merge_blobs(
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            "HEAD",
+            "topic",
+        );
+        (String::from_utf8(merged.content).unwrap(), merged.conflicts)
+    }
+
+    #[test]
+    fn changes_to_different_lines_merge_cleanly() {
+        let base = "1\n2\n3\n4\n5\n";
+        let ours = "one\n2\n3\n4\n5\n";
+        let theirs = "1\n2\n3\n4\nfive\nsix\n";
+        assert_eq!(
+            merge(base, ours, theirs),
+            ("one\n2\n3\n4\nfive\nsix\n".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn one_sided_and_identical_changes_merge_cleanly() {
+        assert_eq!(merge("a\n", "a\n", "b\n"), ("b\n".to_string(), 0));
+        assert_eq!(merge("a\n", "b\n", "a\n"), ("b\n".to_string(), 0));
+        assert_eq!(
+            merge("a\nb\nc\n", "a\nx\nc\n", "a\nx\nc\n"),
+            ("a\nx\nc\n".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn changes_to_the_same_lines_conflict() {
+        let (content, conflicts) =
+            merge("1\n2\n3\n", "1\nours\n3\n", "1\ntheirs\n3\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            content,
+            "1\n<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n3\n"
+        );
+    }
+
+    #[test]
+    fn each_conflicting_chunk_is_counted() {
+        let base = "a\n1\n2\n3\n4\nb\n";
+        let ours = "A\n1\n2\n3\n4\nB\n";
+        let theirs = "α\n1\n2\n3\n4\nβ\n";
+        assert_eq!(merge(base, ours, theirs).1, 2);
+    }
+
+    #[test]
+    fn markers_start_their_own_line() {
+        let (content, conflicts) = merge("a\n", "ours", "theirs");
+        assert_eq!(conflicts, 1);
+        assert_eq!(
+            content,
+            "<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n"
+        );
+    }
+
+    #[test]
+    fn binary_files_changed_on_both_sides_keep_ours() {
+        let merged = merge_blobs(b"\0a", b"\0b", b"\0c", "HEAD", "topic");
+        assert_eq!(
+            (merged.content.as_slice(), merged.conflicts),
+            (&b"\0b"[..], 1)
+        );
+        let merged = merge_blobs(b"\0a", b"\0a", b"\0c", "HEAD", "topic");
+        assert_eq!(
+            (merged.content.as_slice(), merged.conflicts),
+            (&b"\0c"[..], 0)
+        );
+    }
+
+    #[test]
+    fn trees_merge_per_path() {
+        let mut storage = JsonStorage::new();
+        let mut blob = |content: &str| {
+            storage
+                .hash_object(Object::blob(content.as_bytes().to_vec()))
+                .unwrap()
+        };
+        let (a, a2, b, c, d, d2, e1, e2) = (
+            blob("a\n"),
+            blob("a2\n"),
+            blob("b\n"),
+            blob("c\n"),
+            blob("d\n"),
+            blob("d2\n"),
+            blob("e1\n"),
+            blob("e2\n"),
+        );
+        let map = |files: &[(&str, &Hash)]| -> BTreeMap<String, Hash> {
+            files
+                .iter()
+                .map(|(path, hash)| (path.to_string(), (*hash).clone()))
+                .collect()
+        };
+        // a: changed by them, b: deleted by us, c: added by them,
+        // d: modified by us and deleted by them, e: added differently
+        let base = map(&[("a", &a), ("b", &b), ("d", &d)]);
+        let ours = map(&[("a", &a), ("d", &d2), ("e", &e1)]);
+        let theirs = map(&[("a", &a2), ("b", &b), ("c", &c), ("e", &e2)]);
+
+        let merge =
+            merge_trees(&mut storage, &base, &ours, &theirs, "HEAD", "topic")
+                .unwrap();
+        assert_eq!(
+            merge.files,
+            map(&[("a", &a2), ("c", &c), ("d", &d2), ("e", &e1)])
+        );
+        let conflicts: Vec<_> = merge
+            .conflicts
+            .iter()
+            .map(|conflict| (conflict.path.as_str(), conflict.kind))
+            .collect();
+        assert_eq!(
+            conflicts,
+            [
+                ("d", ConflictKind::ModifyDelete),
+                ("e", ConflictKind::AddAdd)
+            ]
+        );
+        assert_eq!(merge.merged, ["e"]);
+    }
+}
@@ -0,0 +1,551 @@
+use core::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha1::{Digest, Sha1};
+
+// a sha1 object id, written as 40 lowercase hex digits
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct Hash(pub Vec<u8>);
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseHashError(String);
+
+impl fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a 40-digit hex object id: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseHashError {}
+
+impl FromStr for Hash {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 40 {
+            return Err(ParseHashError(s.to_string()));
+        }
+        Hash::from_hex(s).ok_or_else(|| ParseHashError(s.to_string()))
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Hash {
+    pub fn to_hex(&self) -> String {
+        self.0
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // the 7-digit abbreviation used in one-line output
+    pub fn short(&self) -> String {
+        self.to_hex()[..7].to_string()
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Hash)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobHash(pub Hash);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Object {
+    Blob {
+        hash: BlobHash,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+    },
+    Tree {
+        hash: Hash,
+        contents: Vec<(String, ObjectMode, Hash)>,
+    },
+    Commit {
+        hash: Hash,
+        message: String,
+        tree_hash: Hash,
+        // first parent first; more than one for merge commits
+        parents: Vec<Hash>,
+        author: Signature,
+        committer: Signature,
+    },
+}
+
+// who made a change and when, as recorded on author/committer lines
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    // seconds since the unix epoch
+    pub time: i64,
+    // timezone offset from UTC in minutes
+    pub offset: i32,
+}
+
+impl Signature {
+    pub fn new(name: String, email: String, time: i64, offset: i32) -> Self {
+        Self {
+            name,
+            email,
+            time,
+            offset,
+        }
+    }
+
+    // "Name <email> 1700000000 +0900"
+    pub fn to_git(&self) -> String {
+        format!(
+            "{} <{}> {} {}",
+            self.name,
+            self.email,
+            self.time,
+            format_offset(self.offset)
+        )
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let (name, rest) = line.split_once('<')?;
+        let (email, date) = rest.split_once('>')?;
+        let (time, offset) = parse_date(date.trim())?;
+
+        Some(Self::new(
+            name.trim().to_string(),
+            email.to_string(),
+            time,
+            offset,
+        ))
+    }
+
+    // the date as git log prints it, e.g. "Thu Nov 14 22:13:20 2023 +0900"
+    pub fn format_date(&self) -> String {
+        const WEEKDAYS: [&str; 7] =
+            ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep",
+            "Oct", "Nov", "Dec",
+        ];
+
+        let local = self.time + self.offset as i64 * 60;
+        let days = local.div_euclid(86400);
+        let secs = local.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+
+        format!(
+            "{} {} {} {:02}:{:02}:{:02} {} {}",
+            WEEKDAYS[days.rem_euclid(7) as usize],
+            MONTHS[month as usize - 1],
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            year,
+            format_offset(self.offset)
+        )
+    }
+}
+
+// parse git's raw "<unix seconds> <+hhmm>" date form
+pub fn parse_date(date: &str) -> Option<(i64, i32)> {
+    let (time, offset) = date.split_once(' ')?;
+    let time = time.parse().ok()?;
+
+    let (sign, digits) = match offset.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4
+        || !digits
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+
+    Some((time, sign * (hours * 60 + minutes)))
+}
+
+// a date given on the command line, as unix seconds: "YYYY-MM-DD",
+// "YYYY-MM-DD HH:MM[:SS]" (both UTC), "@<seconds>", or git's
+// "<seconds> <+hhmm>"
+pub fn parse_user_date(date: &str) -> Option<i64> {
+    let date = date.trim();
+    if let Some(secs) = date.strip_prefix('@') {
+        return secs.parse().ok();
+    }
+    if let Some((time, _)) = parse_date(date) {
+        return Some(time);
+    }
+
+    let (day, time) = date
+        .split_once([' ', 'T'])
+        .unwrap_or((date, "00:00"));
+    let mut day = day.splitn(3, '-');
+    let year = day.next()?.parse().ok()?;
+    let month = day.next()?.parse().ok()?;
+    let day = day.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut time = time.splitn(3, ':');
+    let hours: i64 = time.next()?.parse().ok()?;
+    let minutes: i64 = time.next()?.parse().ok()?;
+    let seconds: i64 = match time.next() {
+        Some(seconds) => seconds.parse().ok()?,
+        None => 0,
+    };
+
+    Some(
+        days_from_civil(year, month, day) * 86400
+            + hours * 3600
+            + minutes * 60
+            + seconds,
+    )
+}
+
+fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{:02}{:02}", sign, offset / 60, offset % 60)
+}
+
+// days since the epoch to a (year, month, day) proleptic gregorian date,
+// after Howard Hinnant's civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// the inverse of `civil_from_days`
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ObjectMode {
+    Blob,
+    Tree,
+    Commit,
+}
+
+impl ObjectMode {
+    // the octal mode git writes in front of every tree entry
+    fn as_str(&self) -> &'static str {
+        match self {
+            ObjectMode::Blob => "100644",
+            ObjectMode::Tree => "40000",
+            ObjectMode::Commit => "160000",
+        }
+    }
+
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "100644" | "100755" | "120000" => Some(ObjectMode::Blob),
+            "40000" | "040000" => Some(ObjectMode::Tree),
+            "160000" => Some(ObjectMode::Commit),
+            _ => None,
+        }
+    }
+}
+
+impl Object {
+    pub fn hash(&self) -> Hash {
+        match self {
+            Object::Blob { hash, .. } => hash.clone().0,
+            Object::Tree { hash, .. } => hash.clone(),
+            Object::Commit { hash, .. } => hash.clone(),
+        }
+    }
+
+    pub fn blob(content: Vec<u8>) -> Self {
+        let hash = hash_encoded("blob", &content);
+
+        Object::Blob {
+            hash: BlobHash(hash),
+            content,
+        }
+    }
+
+    pub fn tree(mut contents: Vec<(String, ObjectMode, Hash)>) -> Self {
+        contents.sort_by_key(tree_sort_key);
+
+        let hash = hash_encoded("tree", &tree_body(&contents));
+
+        Object::Tree { hash, contents }
+    }
+
+    pub fn commit(
+        message: String,
+        tree_hash: Hash,
+        parents: Vec<Hash>,
+        author: Signature,
+        committer: Signature,
+    ) -> Self {
+        let hash = hash_encoded(
+            "commit",
+            &commit_body(&message, &tree_hash, &parents, &author, &committer),
+        );
+
+        Object::Commit {
+            hash,
+            message,
+            tree_hash,
+            parents,
+            author,
+            committer,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Object::Blob { .. } => "blob",
+            Object::Tree { .. } => "tree",
+            Object::Commit { .. } => "commit",
+        }
+    }
+
+    fn body(&self) -> Vec<u8> {
+        match self {
+            Object::Blob { content, .. } => content.clone(),
+            Object::Tree { contents, .. } => tree_body(contents),
+            Object::Commit {
+                message,
+                tree_hash,
+                parents,
+                author,
+                committer,
+                ..
+            } => commit_body(message, tree_hash, parents, author, committer),
+        }
+    }
+
+    // the canonical "<type> <len>\0<body>" form, whose sha1 is the object id
+    pub fn encode(&self) -> Vec<u8> {
+        encode(self.kind(), &self.body())
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let nul = data.iter().position(|&b| b == 0)?;
+        let header = std::str::from_utf8(&data[..nul]).ok()?;
+        let (kind, len) = header.split_once(' ')?;
+        let body = &data[nul + 1..];
+        if len.parse::<usize>().ok()? != body.len() {
+            return None;
+        }
+
+        let hash = hash_encoded(kind, body);
+
+        match kind {
+            "blob" => Some(Object::Blob {
+                hash: BlobHash(hash),
+                content: body.to_vec(),
+            }),
+            "tree" => {
+                let mut contents = Vec::new();
+                let mut rest = body;
+                while !rest.is_empty() {
+                    // "<mode> <name>\0<id>", the space within the first part
+                    let nul = rest.iter().position(|&b| b == 0)?;
+                    let space = rest[..nul]
+                        .iter()
+                        .position(|&b| b == b' ')?;
+                    let mode = std::str::from_utf8(&rest[..space]).ok()?;
+                    let name =
+                        std::str::from_utf8(&rest[space + 1..nul]).ok()?;
+                    let entry_hash = rest.get(nul + 1..nul + 21)?;
+
+                    contents.push((
+                        name.to_string(),
+                        ObjectMode::parse(mode)?,
+                        Hash(entry_hash.to_vec()),
+                    ));
+                    rest = &rest[nul + 21..];
+                }
+
+                Some(Object::Tree { hash, contents })
+            }
+            "commit" => {
+                let body = std::str::from_utf8(body).ok()?;
+                let (headers, message) = body.split_once("\n\n")?;
+
+                let mut tree_hash = None;
+                let mut parents = Vec::new();
+                let mut author = None;
+                let mut committer = None;
+                for line in headers.lines() {
+                    let (key, value) = line.split_once(' ')?;
+                    match key {
+                        "tree" => tree_hash = Some(Hash::from_hex(value)?),
+                        "parent" => parents.push(Hash::from_hex(value)?),
+                        "author" => author = Some(Signature::parse(value)?),
+                        "committer" => {
+                            committer = Some(Signature::parse(value)?)
+                        }
+                        _ => {}
+                    }
+                }
+
+                Some(Object::Commit {
+                    hash,
+                    message: message.to_string(),
+                    tree_hash: tree_hash?,
+                    parents,
+                    author: author?,
+                    committer: committer?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn encode(kind: &str, body: &[u8]) -> Vec<u8> {
+    let mut data = format!("{} {}\0", kind, body.len()).into_bytes();
+    data.extend_from_slice(body);
+    data
+}
+
+fn hash_encoded(kind: &str, body: &[u8]) -> Hash {
+    Hash(Sha1::digest(encode(kind, body)).to_vec())
+}
+
+// git orders tree entries by name, comparing subtrees as if they ended in '/'
+fn tree_sort_key(entry: &(String, ObjectMode, Hash)) -> Vec<u8> {
+    let mut key = entry.0.as_bytes().to_vec();
+    if let ObjectMode::Tree = entry.1 {
+        key.push(b'/');
+    }
+    key
+}
+
+fn tree_body(contents: &[(String, ObjectMode, Hash)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, mode, hash) in contents {
+        body.extend_from_slice(
+            format!("{} {}\0", mode.as_str(), name).as_bytes(),
+        );
+        body.extend_from_slice(&hash.0);
+    }
+    body
+}
+
+fn commit_body(
+    message: &str,
+    tree_hash: &Hash,
+    parents: &[Hash],
+    author: &Signature,
+    committer: &Signature,
+) -> Vec<u8> {
+    let mut body = format!("tree {}\n", tree_hash.to_hex());
+    for parent in parents {
+        body.push_str(&format!("parent {}\n", parent.to_hex()));
+    }
+    body.push_str(&format!("author {}\n", author.to_git()));
+    body.push_str(&format!("committer {}\n", committer.to_git()));
+    body.push('\n');
+    body.push_str(message);
+    body.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an encoded object of `kind` around `body`, as stored
+    fn raw(kind: &str, body: &[u8]) -> Vec<u8> {
+        let mut data = format!("{} {}\0", kind, body.len()).into_bytes();
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn a_tree_decodes_back_to_itself() {
+        let blob = Object::blob(b"a\n".to_vec()).hash();
+        let tree = Object::tree(vec![
+            ("a b.txt".to_string(), ObjectMode::Blob, blob.clone()),
+            ("dir".to_string(), ObjectMode::Tree, blob),
+        ]);
+        let decoded = Object::decode(&tree.encode()).unwrap();
+        assert_eq!(decoded.hash(), tree.hash());
+        assert_eq!(decoded.encode(), tree.encode());
+    }
+
+    #[test]
+    fn a_malformed_tree_entry_is_rejected() {
+        let id = [7u8; 20];
+        let entry = |head: &[u8]| {
+            let mut body = head.to_vec();
+            body.extend_from_slice(&id);
+            raw("tree", &body)
+        };
+        assert!(Object::decode(&entry(b"100644 a\0")).is_some());
+
+        // the NUL before any space
+        assert!(Object::decode(&entry(b"100644\0")).is_none());
+        assert!(Object::decode(&entry(b"100644\0a b")).is_none());
+        // no NUL at all, or too short an id after it
+        assert!(Object::decode(&raw("tree", b"100644 a")).is_none());
+        assert!(Object::decode(&raw("tree", b"100644 a\0short")).is_none());
+        assert!(Object::decode(&entry(b"777 a\0")).is_none());
+    }
+}
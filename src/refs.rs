@@ -0,0 +1,507 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use vfs::{FileSystem, VfsFileType};
+
+use crate::{
+    Error, Hash, ParseHashError, Result, Signature,
+    storage::{create_dir_all, read_text, write_atomic},
+};
+
+// where HEAD points: a branch ref, or straight at a commit when detached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Head {
+    // full ref name, e.g. "refs/heads/main", which need not exist yet
+    Symbolic(String),
+    Detached(Hash),
+}
+
+// the value a ref must hold for an update to go through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Any,
+    // the ref must not exist yet
+    Missing,
+    Is(Hash),
+}
+
+impl Expected {
+    // what to expect of a ref last seen holding `hash`
+    pub fn from_current(hash: Option<Hash>) -> Self {
+        match hash {
+            Some(hash) => Expected::Is(hash),
+            None => Expected::Missing,
+        }
+    }
+
+    pub(crate) fn check(
+        &self,
+        name: &str,
+        current: Option<&Hash>,
+    ) -> Result<()> {
+        let reason = match (self, current) {
+            (Expected::Any, _) | (Expected::Missing, None) => return Ok(()),
+            (Expected::Is(expected), Some(current)) if expected == current => {
+                return Ok(());
+            }
+            (Expected::Missing, Some(_)) => "already exists".to_string(),
+            (Expected::Is(_), None) => "does not exist".to_string(),
+            (Expected::Is(expected), Some(current)) => {
+                format!("is at {} but expected {}", current, expected)
+            }
+        };
+        Err(Error::RefUpdate(name.to_string(), reason))
+    }
+}
+
+// one change of a ref, as a line of its reflog:
+// "<old> <new> Name <email> <time> <offset>\t<message>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    // None when the ref was created
+    pub old: Option<Hash>,
+    pub new: Hash,
+    pub who: Signature,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    pub fn to_line(&self) -> String {
+        let old = match &self.old {
+            Some(old) => old.to_hex(),
+            None => "0".repeat(40),
+        };
+        format!(
+            "{} {} {}\t{}\n",
+            old,
+            self.new,
+            self.who.to_git(),
+            self.message
+        )
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let (head, message) = line
+            .split_once('\t')
+            .unwrap_or((line, ""));
+        let (old, rest) = head.split_once(' ')?;
+        let (new, who) = rest.split_once(' ')?;
+        let old = match old.bytes().all(|byte| byte == b'0') {
+            true => None,
+            false => Some(old.parse().ok()?),
+        };
+
+        Some(Self {
+            old,
+            new: new.parse().ok()?,
+            who: Signature::parse(who)?,
+            message: message
+                .trim_end_matches('\n')
+                .to_string(),
+        })
+    }
+}
+
+// the commit being merged while a merge waits for its conflicts to be
+// resolved
+pub const MERGE_HEAD: &str = "MERGE_HEAD";
+
+// a shortened ref name for display, e.g. "main" for "refs/heads/main"
+pub fn short_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+// the rules of `git check-ref-format`: slash-separated components that are
+// non-empty, do not start with a dot or end in ".lock", and avoid the
+// characters revision syntax gives a meaning to
+pub fn check_ref_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name.contains("..")
+        || name.contains("@{")
+        || name == "@"
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        || name.split('/').any(|component| {
+            component.is_empty()
+                || component.starts_with('.')
+                || component.ends_with(".lock")
+        });
+
+    if invalid {
+        return Err(Error::InvalidRefName(name.to_string()));
+    }
+    Ok(())
+}
+
+// refs stored one file per ref below `mount`, each holding a hex commit id,
+// plus HEAD holding either "ref: <name>" or a hex id
+#[derive(Debug)]
+pub struct RefStore {
+    mount: PathBuf,
+    // where the file system is rooted on disk, when it is a PhysicalFS
+    disk_root: Option<PathBuf>,
+}
+
+impl RefStore {
+    pub fn new(mount: impl Into<PathBuf>) -> Self {
+        Self {
+            mount: mount.into(),
+            disk_root: None,
+        }
+    }
+
+    // the same refs on a PhysicalFS rooted at `root`, whose lock files can
+    // then be created exclusively
+    pub fn on_disk(
+        mount: impl Into<PathBuf>,
+        root: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            mount: mount.into(),
+            disk_root: Some(root.into()),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.mount.join(name)
+    }
+
+    fn parse(path: &Path, hex: &str) -> Result<Hash> {
+        hex.trim()
+            .parse()
+            .map_err(|err: ParseHashError| {
+                Error::CorruptFile(path.display().to_string(), err.to_string())
+            })
+    }
+
+    pub fn head(&self, fs: &dyn FileSystem) -> Result<Head> {
+        let path = self.path("HEAD");
+        let Some(head) = read_text(fs, &path)? else {
+            return Err(Error::NotARepository(self.mount.clone()));
+        };
+
+        match head.trim().strip_prefix("ref: ") {
+            Some(name) => Ok(Head::Symbolic(name.to_string())),
+            None => Ok(Head::Detached(Self::parse(&path, &head)?)),
+        }
+    }
+
+    pub fn set_head(&self, head: &Head, fs: &dyn FileSystem) -> Result<()> {
+        let content = match head {
+            Head::Symbolic(name) => {
+                check_ref_name(name)?;
+                format!("ref: {}\n", name)
+            }
+            Head::Detached(hash) => format!("{}\n", hash),
+        };
+        write_atomic(fs, &self.path("HEAD"), content.as_bytes())
+    }
+
+    // the commit `name` ("refs/heads/main", or "HEAD" followed through
+    // its branch) points at, None when it does not exist
+    pub fn read(
+        &self,
+        name: &str,
+        fs: &dyn FileSystem,
+    ) -> Result<Option<Hash>> {
+        if name == "HEAD" {
+            return match self.head(fs)? {
+                Head::Symbolic(name) => self.read(&name, fs),
+                Head::Detached(hash) => Ok(Some(hash)),
+            };
+        }
+
+        check_ref_name(name)?;
+        let path = self.path(name);
+        let path_str = path.to_string_lossy();
+        if !fs.exists(&path_str)?
+            || fs.metadata(&path_str)?.file_type != VfsFileType::File
+        {
+            return Ok(None);
+        }
+
+        match read_text(fs, &path)? {
+            Some(hex) => Ok(Some(Self::parse(&path, &hex)?)),
+            None => Ok(None),
+        }
+    }
+
+    // point `name` at `hash` if it currently matches `expected`. The ref
+    // is locked while it is compared and written; on disk (see `on_disk`)
+    // two writers racing on the same ref cannot both succeed.
+    pub fn update(
+        &self,
+        name: &str,
+        hash: &Hash,
+        expected: &Expected,
+        fs: &dyn FileSystem,
+    ) -> Result<()> {
+        check_ref_name(name)?;
+        let path = self.path(name);
+
+        // a ref cannot sit where another ref's directory is, or below one
+        for ancestor in Path::new(name).ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            let ancestor = self.path(&ancestor.to_string_lossy());
+            let ancestor = ancestor.to_string_lossy();
+            if fs.exists(&ancestor)?
+                && fs.metadata(&ancestor)?.file_type == VfsFileType::File
+            {
+                return Err(Error::RefUpdate(
+                    name.to_string(),
+                    format!("'{}' exists", ancestor.trim_start_matches('/')),
+                ));
+            }
+        }
+        let path_str = path.to_string_lossy();
+        if fs.exists(&path_str)?
+            && fs.metadata(&path_str)?.file_type == VfsFileType::Directory
+        {
+            return Err(Error::RefUpdate(
+                name.to_string(),
+                "there are refs below it".to_string(),
+            ));
+        }
+
+        self.locked(name, fs, || {
+            expected.check(name, self.read(name, fs)?.as_ref())?;
+            if let Some(parent) = path.parent() {
+                create_dir_all(fs, parent)?;
+            }
+            write_atomic(fs, &path, format!("{}\n", hash).as_bytes())
+        })
+    }
+
+    // remove `name` if it currently matches `expected`, along with any
+    // directories left empty below refs/heads and refs/tags
+    pub fn delete(
+        &self,
+        name: &str,
+        expected: &Expected,
+        fs: &dyn FileSystem,
+    ) -> Result<()> {
+        check_ref_name(name)?;
+
+        self.locked(name, fs, || {
+            let current = self.read(name, fs)?;
+            if current.is_none() {
+                return Err(Error::RefUpdate(
+                    name.to_string(),
+                    "does not exist".to_string(),
+                ));
+            }
+            expected.check(name, current.as_ref())?;
+            fs.remove_file(&self.path(name).to_string_lossy())
+                .map_err(Error::from)
+        })?;
+
+        // only once the lock is gone can its directory be empty
+        let mut dir = Path::new(name).parent();
+        while let Some(parent) = dir {
+            if parent.components().count() <= 2 {
+                break;
+            }
+            let parent_path = self.path(&parent.to_string_lossy());
+            let parent_path = parent_path.to_string_lossy();
+            if fs
+                .read_dir(&parent_path)?
+                .next()
+                .is_some()
+            {
+                break;
+            }
+            fs.remove_dir(&parent_path)?;
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+
+    // every ref whose full name starts with `prefix`, e.g. "refs/heads/",
+    // sorted by name
+    pub fn list(
+        &self,
+        prefix: &str,
+        fs: &dyn FileSystem,
+    ) -> Result<Vec<(String, Hash)>> {
+        let mut refs = Vec::new();
+        let mut dirs = vec!["refs".to_string()];
+        while let Some(dir) = dirs.pop() {
+            let dir_path = self.path(&dir);
+            let dir_path = dir_path.to_string_lossy();
+            if !fs.exists(&dir_path)? {
+                continue;
+            }
+
+            for file_name in fs.read_dir(&dir_path)? {
+                let name = format!("{}/{}", dir, file_name);
+                let path = self.path(&name);
+                if fs
+                    .metadata(&path.to_string_lossy())?
+                    .file_type
+                    == VfsFileType::Directory
+                {
+                    dirs.push(name);
+                } else if name.starts_with(prefix)
+                    && !name.ends_with(".lock")
+                    && let Some(hex) = read_text(fs, &path)?
+                {
+                    refs.push((name, Self::parse(&path, &hex)?));
+                }
+            }
+        }
+        refs.sort();
+        Ok(refs)
+    }
+
+    fn log_path(&self, name: &str) -> PathBuf {
+        self.mount.join("logs").join(name)
+    }
+
+    // the changes recorded for `name`, oldest first
+    pub fn read_log(
+        &self,
+        name: &str,
+        fs: &dyn FileSystem,
+    ) -> Result<Vec<ReflogEntry>> {
+        let path = self.log_path(name);
+        let Some(log) = read_text(fs, &path)? else {
+            return Ok(Vec::new());
+        };
+        log.lines()
+            .map(|line| {
+                ReflogEntry::parse(line).ok_or_else(|| {
+                    Error::CorruptFile(
+                        path.display().to_string(),
+                        format!("bad reflog line '{}'", line),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn append_log(
+        &self,
+        name: &str,
+        entry: &ReflogEntry,
+        fs: &dyn FileSystem,
+    ) -> Result<()> {
+        let path = self.log_path(name);
+        let path_str = path.to_string_lossy();
+        let mut file = if fs.exists(&path_str)? {
+            fs.append_file(&path_str)?
+        } else {
+            if let Some(parent) = path.parent() {
+                create_dir_all(fs, parent)?;
+            }
+            fs.create_file(&path_str)?
+        };
+        file.write_all(entry.to_line().as_bytes())?;
+        Ok(())
+    }
+
+    // move the log of `old`, if any, to `new`
+    pub fn rename_log(
+        &self,
+        old: &str,
+        new: &str,
+        fs: &dyn FileSystem,
+    ) -> Result<()> {
+        let Some(log) = read_text(fs, &self.log_path(old))? else {
+            return Ok(());
+        };
+        let path = self.log_path(new);
+        if let Some(parent) = path.parent() {
+            create_dir_all(fs, parent)?;
+        }
+        write_atomic(fs, &path, log.as_bytes())?;
+        self.delete_log(old, fs)
+    }
+
+    // remove the log of `name` along with directories that leaves empty,
+    // so a later ref can take one's name
+    pub fn delete_log(&self, name: &str, fs: &dyn FileSystem) -> Result<()> {
+        let path = self.log_path(name);
+        let path_str = path.to_string_lossy();
+        if !fs.exists(&path_str)? {
+            return Ok(());
+        }
+        fs.remove_file(&path_str)?;
+
+        let mut dir = Path::new(name).parent();
+        while let Some(parent) = dir {
+            if parent.components().count() <= 2 {
+                break;
+            }
+            let parent_path = self.log_path(&parent.to_string_lossy());
+            let parent_path = parent_path.to_string_lossy();
+            if fs
+                .read_dir(&parent_path)?
+                .next()
+                .is_some()
+            {
+                break;
+            }
+            fs.remove_dir(&parent_path)?;
+            dir = parent.parent();
+        }
+        Ok(())
+    }
+
+    // run `action` holding "<name>.lock", failing if another writer holds
+    // it. The vfs traits have no exclusive create, so only on disk is taking
+    // the lock atomic; elsewhere a writer racing between the check and the
+    // create can take it too.
+    fn locked<T>(
+        &self,
+        name: &str,
+        fs: &dyn FileSystem,
+        action: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let lock_path = format!("{}.lock", self.path(name).to_string_lossy());
+        let held = || {
+            Error::RefUpdate(
+                name.to_string(),
+                format!("'{}' exists", lock_path.trim_start_matches('/')),
+            )
+        };
+        if let Some(parent) = Path::new(&lock_path).parent() {
+            create_dir_all(fs, parent)?;
+        }
+        match &self.disk_root {
+            Some(root) => {
+                let path = root.join(lock_path.trim_start_matches('/'));
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .map_err(|err| match err.kind() {
+                        io::ErrorKind::AlreadyExists => held(),
+                        _ => err.into(),
+                    })?;
+            }
+            None => {
+                if fs.exists(&lock_path)? {
+                    return Err(held());
+                }
+                fs.create_file(&lock_path)?;
+            }
+        }
+
+        let result = action();
+        // write_atomic already moved its own lock over the ref on success
+        if fs.exists(&lock_path)? {
+            fs.remove_file(&lock_path)?;
+        }
+        result
+    }
+}
@@ -0,0 +1,459 @@
+use crate::{Error, Hash, Object, ObjectMode, Result, Storage, merge_bases};
+
+// what a revision expression stands for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RevSpec {
+    // one object, e.g. "main~2" or "HEAD:src/lib.rs"
+    Single(Hash),
+    // "A..B": the commits reachable from B but not from A
+    Range(Hash, Hash),
+    // "A...B": the commits reachable from either but not from both
+    Symmetric(Hash, Hash),
+    // "^A": leave out the commits reachable from A
+    Exclude(Hash),
+}
+
+impl RevSpec {
+    // the commits a history walk starts from and those whose ancestry it
+    // leaves out, as `log` and `rev-parse` spell a range
+    pub fn tips(
+        &self,
+        storage: &impl Storage,
+    ) -> Result<(Vec<Hash>, Vec<Hash>)> {
+        Ok(match self {
+            RevSpec::Single(hash) => (vec![hash.clone()], Vec::new()),
+            RevSpec::Range(from, to) => (vec![to.clone()], vec![from.clone()]),
+            RevSpec::Symmetric(a, b) => {
+                (vec![a.clone(), b.clone()], merge_bases(storage, a, b)?)
+            }
+            RevSpec::Exclude(hash) => (Vec::new(), vec![hash.clone()]),
+        })
+    }
+}
+
+// parse a revision expression the way `git rev-parse` does: a single
+// revision (see `parse_single`), "A..B" or "A...B" with HEAD for a missing
+// side, or "^A"
+pub fn rev_parse(
+    storage: &(impl Storage + ?Sized),
+    expr: &str,
+) -> Result<RevSpec> {
+    let side = |rev: &str| match rev {
+        "" => parse_single(storage, "HEAD"),
+        rev => parse_single(storage, rev),
+    };
+
+    // a path after ':' may contain dots of its own
+    if !expr.contains(':') {
+        if let Some((a, b)) = expr.split_once("...") {
+            return Ok(RevSpec::Symmetric(side(a)?, side(b)?));
+        }
+        if let Some((a, b)) = expr.split_once("..") {
+            return Ok(RevSpec::Range(side(a)?, side(b)?));
+        }
+    }
+    if let Some(rev) = expr.strip_prefix('^') {
+        return Ok(RevSpec::Exclude(parse_single(storage, rev)?));
+    }
+    Ok(RevSpec::Single(parse_single(storage, expr)?))
+}
+
+// the object one revision names:
+//   a name: "HEAD" or "@", a branch or tag, "refs/heads/main", or a full
+//     or abbreviated object id
+//   "<name>@{n}": the value the ref had n changes ago, with "@{n}" alone
+//     meaning the current branch
+//   followed by any of "~n" (n first parents back), "^n" (the nth
+//     parent, "^0" the commit itself) and "^{tree}" or "^{commit}"
+//   "<rev>:<path>": the blob or tree at `path` in that commit, and
+//     ":<path>" or ":<stage>:<path>" the staged one
+pub fn parse_single(
+    storage: &(impl Storage + ?Sized),
+    expr: &str,
+) -> Result<Hash> {
+    let bad = || Error::BadRevision(expr.to_string());
+
+    if let Some((rev, path)) = expr.split_once(':') {
+        if rev.is_empty() {
+            return staged_blob(storage, path).ok_or_else(bad);
+        }
+        let tree = tree_of(storage, &parse_single(storage, rev)?)?;
+        return tree_path(storage, tree, path)?.ok_or_else(bad);
+    }
+
+    let end = expr
+        .find(['~', '^'])
+        .unwrap_or(expr.len());
+    let (base, mut suffix) = expr.split_at(end);
+    let mut hash = match base.split_once("@{") {
+        Some((name, n)) => {
+            let n = n.strip_suffix('}').ok_or_else(bad)?;
+            reflog_value(storage, name, n)?.ok_or_else(bad)?
+        }
+        None => storage.resolve_name(base)?,
+    };
+
+    while let Some(op) = suffix.chars().next() {
+        if op != '~' && op != '^' {
+            return Err(bad());
+        }
+        suffix = &suffix[op.len_utf8()..];
+
+        if op == '^'
+            && let Some(peel) = suffix.strip_prefix('{')
+        {
+            let (kind, rest) = peel.split_once('}').ok_or_else(bad)?;
+            hash = match kind {
+                "" | "commit" => commit_parents(storage, &hash)
+                    .map(|_| hash)
+                    .ok_or_else(bad)?,
+                "tree" => tree_of(storage, &hash)?,
+                _ => return Err(bad()),
+            };
+            suffix = rest;
+            continue;
+        }
+
+        let digits = suffix
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(suffix.len());
+        let n = match &suffix[..digits] {
+            "" => 1,
+            digits => digits.parse().map_err(|_| bad())?,
+        };
+        suffix = &suffix[digits..];
+
+        let parents = commit_parents(storage, &hash).ok_or_else(bad)?;
+        hash = match op {
+            '~' => {
+                for _ in 0..n {
+                    let parents =
+                        commit_parents(storage, &hash).ok_or_else(bad)?;
+                    hash = parents
+                        .into_iter()
+                        .next()
+                        .ok_or_else(bad)?;
+                }
+                hash
+            }
+            '^' if n == 0 => hash,
+            _ => parents
+                .into_iter()
+                .nth(n - 1)
+                .ok_or_else(bad)?,
+        };
+    }
+    Ok(hash)
+}
+
+// the parents of the commit `hash`, None when it is no commit
+fn commit_parents(
+    storage: &(impl Storage + ?Sized),
+    hash: &Hash,
+) -> Option<Vec<Hash>> {
+    match storage.read_object(hash) {
+        Ok(Object::Commit { parents, .. }) => Some(parents),
+        _ => None,
+    }
+}
+
+// the root tree of the commit `hash`, or `hash` itself when it is a tree
+fn tree_of(storage: &(impl Storage + ?Sized), hash: &Hash) -> Result<Hash> {
+    match storage.read_object(hash)? {
+        Object::Commit { tree_hash, .. } => Ok(tree_hash),
+        Object::Tree { .. } => Ok(hash.clone()),
+        _ => Err(Error::BadRevision(hash.to_hex())),
+    }
+}
+
+// the object at "dir/file" below the tree `tree`, the tree itself for ""
+fn tree_path(
+    storage: &(impl Storage + ?Sized),
+    tree: Hash,
+    path: &str,
+) -> Result<Option<Hash>> {
+    let mut hash = tree;
+    for name in path
+        .split('/')
+        .filter(|name| !name.is_empty())
+    {
+        let Object::Tree { contents, .. } = storage.read_object(&hash)? else {
+            return Ok(None);
+        };
+        let Some((_, _, child)) =
+            contents
+                .into_iter()
+                .find(|(entry, mode, _)| {
+                    entry == name && !matches!(mode, ObjectMode::Commit)
+                })
+        else {
+            return Ok(None);
+        };
+        hash = child;
+    }
+    Ok(Some(hash))
+}
+
+// the blob staged at `path`, which may start with a stage, e.g. "2:file"
+fn staged_blob(storage: &(impl Storage + ?Sized), path: &str) -> Option<Hash> {
+    let (stage, path) = match path.split_once(':') {
+        Some((stage, path)) => (stage.parse().ok()?, path),
+        None => (0, path),
+    };
+    storage
+        .index()
+        .entries()
+        .iter()
+        .find(|entry| entry.file_name() == path && entry.stage() == stage)
+        .map(|entry| entry.blob_hash().clone())
+}
+
+// what the ref `name` ("" for the current branch) pointed at `n` changes
+// ago, from its reflog
+fn reflog_value(
+    storage: &(impl Storage + ?Sized),
+    name: &str,
+    n: &str,
+) -> Result<Option<Hash>> {
+    let Ok(n) = n.parse::<usize>() else {
+        return Ok(None);
+    };
+    let name = match name {
+        "" => storage
+            .head_ref()
+            .unwrap_or("HEAD")
+            .to_string(),
+        "HEAD" | "@" => "HEAD".to_string(),
+        name => match storage.full_ref_name(name)? {
+            Some(name) => name,
+            None => return Ok(None),
+        },
+    };
+
+    let log = storage.reflog(&name)?;
+    Ok(log
+        .into_iter()
+        .rev()
+        .nth(n)
+        .map(|entry| entry.new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Entry, Expected, JsonStorage, Signature};
+
+    // main: c1 - c2 - c3 - m, where m merges side (c1 - s1). The reflog of
+    // main holds c1, c2, c3, m in that order.
+    struct Repo {
+        storage: JsonStorage,
+        c1: Hash,
+        c2: Hash,
+        c3: Hash,
+        s1: Hash,
+        m: Hash,
+        tree: Hash,
+        file: Hash,
+    }
+
+    fn repo() -> Repo {
+        let mut storage = JsonStorage::new();
+        let file = storage
+            .hash_object(Object::blob(b"f\n".to_vec()))
+            .unwrap();
+        let dir = storage
+            .hash_object(Object::tree(vec![(
+                "f".to_string(),
+                ObjectMode::Blob,
+                file.clone(),
+            )]))
+            .unwrap();
+        let tree = storage
+            .hash_object(Object::tree(vec![
+                ("dir".to_string(), ObjectMode::Tree, dir),
+                ("v1..v2".to_string(), ObjectMode::Blob, file.clone()),
+            ]))
+            .unwrap();
+
+        let mut time = 0;
+        let mut commit = |storage: &mut JsonStorage, parents: Vec<Hash>| {
+            time += 1;
+            let sig = Signature::new("A".into(), "a@b".into(), time, 0);
+            let commit = Object::commit(
+                format!("c{}", time),
+                tree.clone(),
+                parents,
+                sig.clone(),
+                sig,
+            );
+            storage.hash_object(commit).unwrap()
+        };
+        let c1 = commit(&mut storage, vec![]);
+        let c2 = commit(&mut storage, vec![c1.clone()]);
+        let c3 = commit(&mut storage, vec![c2.clone()]);
+        let s1 = commit(&mut storage, vec![c1.clone()]);
+        let m = commit(&mut storage, vec![c3.clone(), s1.clone()]);
+
+        for hash in [&c1, &c2, &c3, &m] {
+            storage
+                .update_ref("refs/heads/main", hash.clone(), &Expected::Any)
+                .unwrap();
+        }
+        storage
+            .update_ref("refs/heads/side", s1.clone(), &Expected::Any)
+            .unwrap();
+
+        Repo {
+            storage,
+            c1,
+            c2,
+            c3,
+            s1,
+            m,
+            tree,
+            file,
+        }
+    }
+
+    impl Repo {
+        fn parse(&self, expr: &str) -> Result<Hash> {
+            parse_single(&self.storage, expr)
+        }
+
+        fn is_bad(&self, expr: &str) -> bool {
+            matches!(self.parse(expr), Err(Error::BadRevision(_)))
+        }
+    }
+
+    #[test]
+    fn names() {
+        let repo = repo();
+        for name in ["HEAD", "@", "main", "heads/main", "refs/heads/main"] {
+            assert_eq!(repo.parse(name).unwrap(), repo.m, "{}", name);
+        }
+        assert_eq!(repo.parse("side").unwrap(), repo.s1);
+        assert_eq!(repo.parse(&repo.c2.to_hex()).unwrap(), repo.c2);
+        assert_eq!(repo.parse(&repo.c2.short()).unwrap(), repo.c2);
+    }
+
+    #[test]
+    fn ancestry() {
+        let repo = repo();
+        assert_eq!(repo.parse("HEAD~").unwrap(), repo.c3);
+        assert_eq!(repo.parse("HEAD~2").unwrap(), repo.c2);
+        assert_eq!(repo.parse("main~3").unwrap(), repo.c1);
+        assert_eq!(repo.parse("HEAD~0").unwrap(), repo.m);
+        assert_eq!(repo.parse("HEAD^").unwrap(), repo.c3);
+        assert_eq!(repo.parse("HEAD^1").unwrap(), repo.c3);
+        assert_eq!(repo.parse("HEAD^2").unwrap(), repo.s1);
+        assert_eq!(repo.parse("HEAD^0").unwrap(), repo.m);
+        assert_eq!(repo.parse("HEAD^2~1").unwrap(), repo.c1);
+        assert_eq!(repo.parse("HEAD~1^^").unwrap(), repo.c1);
+        assert!(repo.is_bad("HEAD^3"));
+        assert!(repo.is_bad("HEAD~4"));
+    }
+
+    #[test]
+    fn peeling() {
+        let repo = repo();
+        assert_eq!(repo.parse("HEAD^{tree}").unwrap(), repo.tree);
+        assert_eq!(repo.parse("HEAD~1^{commit}").unwrap(), repo.c3);
+        assert_eq!(repo.parse("HEAD^{}").unwrap(), repo.m);
+        assert!(repo.is_bad("HEAD^{blob}"));
+        assert!(repo.is_bad("HEAD^{tree}^{commit}"));
+        assert!(repo.is_bad("HEAD^{tree}~1"));
+    }
+
+    #[test]
+    fn paths() {
+        let mut repo = repo();
+        assert_eq!(repo.parse("HEAD:dir/f").unwrap(), repo.file);
+        assert_eq!(repo.parse("HEAD~2:v1..v2").unwrap(), repo.file);
+        assert_eq!(repo.parse("HEAD:").unwrap(), repo.tree);
+        assert_eq!(repo.parse("HEAD^{tree}:dir/f").unwrap(), repo.file);
+        assert!(repo.is_bad("HEAD:missing"));
+        assert!(repo.is_bad("HEAD:dir/f/g"));
+
+        let (file, other) = (repo.file.clone(), repo.tree.clone());
+        repo.storage
+            .modify_index(|index| {
+                index.insert(Entry::new("a".to_string(), file));
+                index.insert(Entry::at_stage("b".to_string(), other, 2));
+            })
+            .unwrap();
+        assert_eq!(repo.parse(":a").unwrap(), repo.file);
+        assert_eq!(repo.parse(":2:b").unwrap(), repo.tree);
+        assert!(repo.is_bad(":b"));
+        assert!(repo.is_bad(":c"));
+    }
+
+    #[test]
+    fn reflog() {
+        let repo = repo();
+        assert_eq!(repo.parse("@{0}").unwrap(), repo.m);
+        assert_eq!(repo.parse("@{1}").unwrap(), repo.c3);
+        assert_eq!(repo.parse("main@{3}").unwrap(), repo.c1);
+        assert_eq!(repo.parse("HEAD@{2}").unwrap(), repo.c2);
+        assert_eq!(repo.parse("main@{1}~1").unwrap(), repo.c2);
+        assert_eq!(repo.parse("side@{0}").unwrap(), repo.s1);
+        assert!(repo.is_bad("main@{4}"));
+        assert!(repo.is_bad("main@{x}"));
+        assert!(repo.is_bad("main@{1"));
+        assert!(repo.is_bad("nope@{0}"));
+    }
+
+    #[test]
+    fn malformed() {
+        let repo = repo();
+        for expr in [
+            "",
+            "nope",
+            "HEAD~x",
+            "HEAD~é",
+            "HEAD^é",
+            "HEAD^{tree",
+            "HEAD^{}x",
+            "é",
+        ] {
+            assert!(repo.is_bad(expr), "{:?}", expr);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let repo = repo();
+        let parse = |expr| rev_parse(&repo.storage, expr).unwrap();
+        assert_eq!(parse("HEAD~1"), RevSpec::Single(repo.c3.clone()));
+        assert_eq!(
+            parse("side..main"),
+            RevSpec::Range(repo.s1.clone(), repo.m.clone())
+        );
+        assert_eq!(
+            parse("side.."),
+            RevSpec::Range(repo.s1.clone(), repo.m.clone())
+        );
+        assert_eq!(
+            parse("HEAD~1...side"),
+            RevSpec::Symmetric(repo.c3.clone(), repo.s1.clone())
+        );
+        assert_eq!(parse("^side"), RevSpec::Exclude(repo.s1.clone()));
+        assert_eq!(parse("HEAD:v1..v2"), RevSpec::Single(repo.file.clone()));
+
+        assert_eq!(
+            parse("HEAD~1...side")
+                .tips(&repo.storage)
+                .unwrap(),
+            (
+                vec![repo.c3.clone(), repo.s1.clone()],
+                vec![repo.c1.clone()]
+            )
+        );
+        assert_eq!(
+            parse("side..main")
+                .tips(&repo.storage)
+                .unwrap(),
+            (vec![repo.m.clone()], vec![repo.s1.clone()])
+        );
+        assert!(rev_parse(&repo.storage, "side..nope").is_err());
+    }
+}
@@ -1,376 +1,1858 @@
-use core::fmt;
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
 
-use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
-use vfs::FileSystem;
+use vfs::{FileSystem, VfsFileType};
 
-use crate::FsMap;
+use crate::{
+    ConflictKind, DiffFormat, DiffOptions, Entry, Error, Expected, FileChange,
+    FileStat, Graph, Hash, Head, LogOptions, MERGE_HEAD, Object, ObjectMode,
+    RenameOptions, Result, RevSpec, Status, StatusFormat, Storage,
+    ahead_behind, ancestors, blob_content, check_ref_name, checkout_files,
+    detect_renames, diff_files, diff_trees, format_commit, history, in_mount,
+    is_ancestor, merge_bases, merge_bases_many, merge_trees, name_status,
+    octopus_merge_bases, rev_parse, short_ref_name, shown_parents, stat_path,
+    worktree_files, worktree_hash, write_patch, write_stat,
+    write_worktree_content,
+};
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Hash(pub Vec<u8>);
-
-impl fmt::Debug for Hash {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.0)
-    }
+pub enum Cmd {
+    // `file_name` is a path in the worktree filesystem, e.g. "/dir/file".
+    // Directories are added recursively and a deleted file is dropped from
+    // the index.
+    Add {
+        file_name: String,
+    },
+    // stage every change in the worktree, deletions included
+    AddAll,
+    Commit {
+        message: String,
+    },
+    Status {
+        format: StatusFormat,
+    },
+    Log {
+        options: LogOptions,
+    },
+    // `object` is any revision, HEAD when absent. A commit is shown with
+    // its message, a blob (e.g. "HEAD:file") as its content and a tree as
+    // its entries.
+    Show {
+        object: Option<String>,
+    },
+    Branch {
+        action: BranchAction,
+    },
+    // switch the worktree, index and HEAD to `rev`. A branch name is
+    // checked out as that branch unless `detach`; anything else detaches
+    // HEAD. `new_branch` is first created at `rev`, and `force` throws
+    // away local changes in the way.
+    Checkout {
+        rev: String,
+        new_branch: Option<String>,
+        detach: bool,
+        force: bool,
+    },
+    // with no `revs`, the worktree against the index; `cached` compares
+    // the index against HEAD (or the one rev) instead. One rev without
+    // `cached` is compared against the worktree, two against each other.
+    Diff {
+        cached: bool,
+        revs: Vec<String>,
+        format: DiffFormat,
+        options: DiffOptions,
+    },
+    // merge `rev` into HEAD, fast-forwarding unless `no_ff`; a merge
+    // commit gets `message`, or git's default one
+    Merge {
+        rev: String,
+        message: Option<String>,
+        no_ff: bool,
+    },
+    // print the best common ancestor of `commits`, or every one of them
+    // with `all`. The first commit is compared against a merge of the
+    // others unless `octopus`, which wants ancestors common to all.
+    MergeBase {
+        commits: Vec<String>,
+        all: bool,
+        octopus: bool,
+    },
+    // succeed when `ancestor` is reachable from `commit`
+    IsAncestor {
+        ancestor: String,
+        commit: String,
+    },
+    // print the object id each of `revs` names; a range prints its tip
+    // and then "^" and each commit it leaves out, like git
+    RevParse {
+        revs: Vec<String>,
+    },
+    // print `key`, or set it when `value` is given
+    Config {
+        key: String,
+        value: Option<String>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BlobHash(Hash);
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct G1t {
-    index: Index,
-    objects: Vec<Object>,
+// branch names are short, e.g. "main" for refs/heads/main
+pub enum BranchAction {
+    // `verbose` 1 adds each tip's id and subject, 2 its upstream too
+    List {
+        verbose: u8,
+    },
+    // `start` is a revision, HEAD when absent; `force` moves an existing
+    // branch
+    Create {
+        name: String,
+        start: Option<String>,
+        force: bool,
+    },
+    // refuses a branch not merged into its upstream (or HEAD) unless forced
+    Delete {
+        name: String,
+        force: bool,
+    },
+    // `old` defaults to the current branch
+    Rename {
+        old: Option<String>,
+        new: String,
+    },
+    // `upstream` is another branch `name` (the current one by default)
+    // is compared against
+    SetUpstream {
+        name: Option<String>,
+        upstream: String,
+    },
+    UnsetUpstream {
+        name: Option<String>,
+    },
 }
 
-pub struct Content {
-    file_name: String,
-    content: String,
+pub struct Runner<S: Storage> {
+    pub storage: S,
+    // the worktree, None in a bare repository
+    fs: Option<Box<dyn FileSystem>>,
+    // where command output goes, stdout unless replaced
+    out: Box<dyn Write>,
 }
 
-impl Content {
-    pub fn new(file_name: String, content: String) -> Self {
-        Self { file_name, content }
+impl<S: Storage> Runner<S> {
+    pub fn new(storage: S, fs: Box<dyn FileSystem>) -> Self {
+        Self {
+            storage,
+            fs: Some(fs),
+            out: Box::new(io::stdout()),
+        }
     }
-}
 
-pub trait Storage {
-    fn index(&self) -> &Index;
-    fn objects(&self) -> &Vec<Object>;
-    fn modify_index(&mut self, modifier: Box<dyn FnOnce(&mut Index)>);
-    fn add_entry(&mut self, entry: Entry);
-    // return object hash and store object
-    fn hash_object(&mut self, object: Object) -> Hash;
+    // a runner without a worktree; commands that need one fail with
+    // `Error::BareRepository`
+    pub fn bare(storage: S) -> Self {
+        Self {
+            storage,
+            fs: None,
+            out: Box::new(io::stdout()),
+        }
+    }
 
-    // register file content to index, but not store object
-    fn update_index(&mut self, content: Content);
-}
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
 
-pub struct JsonStorage {
-    index: Index,
-    objects: Vec<Object>,
-    pub fs: Box<dyn FileSystem>,
-}
+    pub fn run(&mut self, cmd: Cmd) -> Result<()> {
+        match cmd {
+            Cmd::Add { file_name } => self.add(&file_name)?,
+            Cmd::AddAll => self.add("/")?,
+            Cmd::Commit { message } => {
+                self.worktree()?;
+                let tree_hash = self.storage.write_tree()?;
 
-impl JsonStorage {
-    pub fn new(fs: Box<dyn FileSystem>) -> Self {
-        Self {
-            index: Index::new(),
-            objects: Vec::new(),
-            fs,
+                // like git, commit messages always end with a newline
+                let mut message = message;
+                if !message.ends_with('\n') {
+                    message.push('\n');
+                }
+
+                // a merge stopped for conflicts gets its second parent now
+                let merge_head = self.storage.read_ref(MERGE_HEAD)?;
+                let commit = Object::commit(
+                    message,
+                    tree_hash,
+                    self.storage
+                        .head()
+                        .into_iter()
+                        .chain(merge_head.clone())
+                        .collect(),
+                    self.storage
+                        .config()
+                        .signature("author"),
+                    self.storage
+                        .config()
+                        .signature("committer"),
+                );
+                let commit_hash = self.storage.hash_object(commit)?;
+
+                self.storage.set_head(commit_hash)?;
+                if let Some(merge_head) = merge_head {
+                    self.storage
+                        .delete_ref(MERGE_HEAD, &Expected::Is(merge_head))?;
+                }
+            }
+            Cmd::Status { format } => {
+                let status = Status::new(&self.storage, self.worktree()?)?;
+                match format {
+                    StatusFormat::Long => status.write_long(&mut self.out)?,
+                    StatusFormat::Porcelain => {
+                        status.write_porcelain(&mut self.out)?
+                    }
+                    StatusFormat::Json => {
+                        serde_json::to_writer_pretty(&mut self.out, &status)
+                            .map_err(io::Error::from)?;
+                        writeln!(self.out)?;
+                    }
+                }
+            }
+            Cmd::Log { options } => self.log(&options)?,
+            Cmd::Show { object } => {
+                let hash = match &object {
+                    Some(object) => self.storage.resolve_rev(object)?,
+                    None => self
+                        .storage
+                        .head()
+                        .ok_or_else(|| Error::BadRevision("HEAD".into()))?,
+                };
+
+                match self.storage.read_object(&hash)? {
+                    commit @ Object::Commit { .. } => {
+                        print_commit(&mut self.out, &commit)?
+                    }
+                    Object::Blob { content, .. } => {
+                        self.out.write_all(&content)?
+                    }
+                    Object::Tree { contents, .. } => {
+                        let name = object.unwrap_or_else(|| hash.to_hex());
+                        writeln!(self.out, "tree {}\n", name)?;
+                        for (name, mode, _) in contents {
+                            let slash = match mode {
+                                ObjectMode::Tree => "/",
+                                _ => "",
+                            };
+                            writeln!(self.out, "{}{}", name, slash)?;
+                        }
+                    }
+                }
+            }
+            Cmd::Branch { action } => self.branch(action)?,
+            Cmd::Diff {
+                cached,
+                revs,
+                format,
+                options,
+            } => self.diff(cached, &revs, format, &options)?,
+            Cmd::Checkout {
+                rev,
+                new_branch,
+                detach,
+                force,
+            } => self.checkout(&rev, new_branch, detach, force)?,
+            Cmd::Merge {
+                rev,
+                message,
+                no_ff,
+            } => self.merge(&rev, message, no_ff)?,
+            Cmd::MergeBase {
+                commits,
+                all,
+                octopus,
+            } => {
+                let commits = commits
+                    .iter()
+                    .map(|rev| self.storage.resolve_rev(rev))
+                    .collect::<Result<Vec<_>>>()?;
+                let bases = match commits.split_first() {
+                    Some((one, others)) if !octopus => {
+                        merge_bases_many(&self.storage, one, others)?
+                    }
+                    _ => octopus_merge_bases(&self.storage, &commits)?,
+                };
+                if bases.is_empty() {
+                    return Err(Error::NoMergeBase);
+                }
+                let shown = if all { bases.len() } else { 1 };
+                for base in &bases[..shown] {
+                    writeln!(self.out, "{}", base)?;
+                }
+            }
+            Cmd::IsAncestor { ancestor, commit } => {
+                let ancestor = self.storage.resolve_rev(&ancestor)?;
+                let commit = self.storage.resolve_rev(&commit)?;
+                if !is_ancestor(&self.storage, &ancestor, &commit)? {
+                    return Err(Error::NotAncestor(ancestor, commit));
+                }
+            }
+            Cmd::RevParse { revs } => {
+                for rev in &revs {
+                    let (tips, excludes) =
+                        rev_parse(&self.storage, rev)?.tips(&self.storage)?;
+                    for tip in tips {
+                        writeln!(self.out, "{}", tip)?;
+                    }
+                    for exclude in excludes {
+                        writeln!(self.out, "^{}", exclude)?;
+                    }
+                }
+            }
+            Cmd::Config { key, value } => match value {
+                Some(value) => self
+                    .storage
+                    .modify_config(|config| config.set(key, value))?,
+                None => {
+                    if let Some(value) = self.storage.config().get(&key) {
+                        writeln!(self.out, "{}", value)?;
+                    }
+                }
+            },
         }
+
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct FsMapedJson {
-    index: Index,
-    objects: FsMap,
-    mount: PathBuf,
-    pub fs: Box<dyn FileSystem>,
-}
+    // stage the file or directory at `file_name` so the index matches the
+    // worktree below it
+    fn add(&mut self, file_name: &str) -> Result<()> {
+        let fs = self
+            .fs
+            .as_deref()
+            .ok_or(Error::BareRepository)?;
 
-impl FsMapedJson {
-    pub fn new(mount: PathBuf, fs: Box<dyn FileSystem>) -> Self {
-        // Self { mount }
-        let index_path = mount.join("index.json");
+        // index paths are relative to the worktree root
+        let path = file_name.trim_start_matches('/');
+        if in_mount(path) {
+            return Err(Error::InvalidPath(path.to_string()));
+        }
+        let is_dir = path.is_empty()
+            || (fs.exists(file_name)?
+                && fs.metadata(file_name)?.file_type == VfsFileType::Directory);
 
-        if !fs
-            .exists(index_path.to_str().unwrap())
-            .unwrap_or(false)
+        let (files, prefix) = if is_dir {
+            let prefix = if path.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", path)
+            };
+            (worktree_files(fs, path)?, prefix)
+        } else if fs.exists(file_name)? {
+            (vec![path.to_string()], path.to_string())
+        } else if self
+            .storage
+            .index()
+            .entries()
+            .iter()
+            .any(|entry| entry.file_name() == path)
         {
-            let mut file = fs
-                .create_file(index_path.to_str().unwrap())
-                .unwrap();
+            (Vec::new(), path.to_string())
+        } else {
+            return Err(Error::PathNotFound(path.to_string()));
+        };
 
-            file.write_all(
-                serde_json::to_string(&Index::default())
-                    .unwrap()
-                    .as_bytes(),
-            )
-            .unwrap();
+        // entries under `prefix` with no file left behind them. A file
+        // that became a directory, or the other way round, leaves its old
+        // entries at `path` itself or below it.
+        let below = format!("{}/", path);
+        let deleted: Vec<String> = self
+            .storage
+            .index()
+            .entries()
+            .iter()
+            .map(|entry| entry.file_name())
+            .filter(|name| {
+                if is_dir {
+                    name.starts_with(&prefix) || *name == path
+                } else {
+                    *name == prefix || name.starts_with(&below)
+                }
+            })
+            .filter(|name| {
+                files
+                    .binary_search_by(|file| file.as_str().cmp(name))
+                    .is_err()
+            })
+            .map(str::to_string)
+            .collect();
+
+        let mut entries = Vec::new();
+        for file in files {
+            let mut content = Vec::new();
+            fs.open_file(&format!("/{}", file))?
+                .read_to_end(&mut content)?;
+            let hash = self
+                .storage
+                .hash_object(Object::blob(content))?;
+            entries.push(Entry::new(file, hash));
         }
-        let mut file = fs
-            .open_file(index_path.to_str().unwrap())
-            .unwrap();
 
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .unwrap();
+        self.storage.modify_index(|index| {
+            for entry in entries {
+                index.insert(entry);
+            }
+            for name in &deleted {
+                index.remove(name);
+            }
+        })
+    }
+
+    fn branch(&mut self, action: BranchAction) -> Result<()> {
+        match action {
+            BranchAction::List { verbose } => {
+                let current = self
+                    .storage
+                    .head_ref()
+                    .map(str::to_string);
+                if current.is_none()
+                    && let Some(head) = self.storage.head()
+                {
+                    writeln!(
+                        self.out,
+                        "* (HEAD detached at {})",
+                        head.short()
+                    )?;
+                }
+
+                let branches = self.storage.refs("refs/heads/")?;
+                let width = branches
+                    .iter()
+                    .map(|(name, _)| short_ref_name(name).len())
+                    .max()
+                    .unwrap_or(0);
+                for (name, hash) in &branches {
+                    let marker = if current.as_ref() == Some(name) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    let short = short_ref_name(name);
+                    if verbose == 0 {
+                        writeln!(self.out, "{} {}", marker, short)?;
+                        continue;
+                    }
+
+                    let mut tracking = String::new();
+                    if verbose > 1
+                        && let Some(upstream) = self.upstream(short)
+                    {
+                        tracking = match self.storage.read_ref(&upstream)? {
+                            Some(base) => {
+                                let (ahead, behind) =
+                                    ahead_behind(&self.storage, hash, &base)?;
+                                let mut counts = Vec::new();
+                                if ahead > 0 {
+                                    counts.push(format!("ahead {}", ahead));
+                                }
+                                if behind > 0 {
+                                    counts.push(format!("behind {}", behind));
+                                }
+                                if counts.is_empty() {
+                                    format!("[{}] ", short_ref_name(&upstream))
+                                } else {
+                                    format!(
+                                        "[{}: {}] ",
+                                        short_ref_name(&upstream),
+                                        counts.join(", ")
+                                    )
+                                }
+                            }
+                            None => {
+                                format!(
+                                    "[{}: gone] ",
+                                    short_ref_name(&upstream)
+                                )
+                            }
+                        };
+                    }
 
-        let index: Index = serde_json::from_str(&content).unwrap();
+                    let subject = match self.storage.read_object(hash)? {
+                        Object::Commit { message, .. } => message
+                            .lines()
+                            .next()
+                            .unwrap_or("")
+                            .to_string(),
+                        _ => String::new(),
+                    };
+                    writeln!(
+                        self.out,
+                        "{} {:<width$} {} {}{}",
+                        marker,
+                        short,
+                        hash.short(),
+                        tracking,
+                        subject,
+                    )?;
+                }
+            }
+            BranchAction::Create { name, start, force } => {
+                let start = start.as_deref().unwrap_or("HEAD");
+                let hash = self.storage.resolve_rev(start)?;
+                if !matches!(
+                    self.storage.read_object(&hash)?,
+                    Object::Commit { .. }
+                ) {
+                    return Err(Error::BadRevision(start.to_string()));
+                }
 
-        // if objects dir not exists, create it
-        if !fs
-            .exists(mount.join("objects").to_str().unwrap())
-            .unwrap_or(false)
+                let full_name = format!("refs/heads/{}", name);
+                if force && self.storage.head_ref() == Some(&full_name) {
+                    return Err(Error::RefUpdate(
+                        full_name,
+                        "cannot force update the current branch".to_string(),
+                    ));
+                }
+                let expected = if force {
+                    Expected::Any
+                } else {
+                    Expected::Missing
+                };
+                self.storage
+                    .update_ref(&full_name, hash, &expected)?;
+            }
+            BranchAction::Delete { name, force } => {
+                let full_name = format!("refs/heads/{}", name);
+                if self.storage.head_ref() == Some(&full_name) {
+                    return Err(Error::RefUpdate(
+                        full_name,
+                        "cannot delete the current branch".to_string(),
+                    ));
+                }
+                let hash = self
+                    .storage
+                    .read_ref(&full_name)?
+                    .ok_or_else(|| {
+                        Error::RefUpdate(
+                            full_name.clone(),
+                            "does not exist".into(),
+                        )
+                    })?;
+
+                if !force {
+                    // merged means reachable from the upstream if there is
+                    // one, otherwise from HEAD
+                    let base = match self.upstream(&name) {
+                        Some(upstream) => self.storage.read_ref(&upstream)?,
+                        None => self.storage.head(),
+                    };
+                    let merged = match base {
+                        Some(base) => is_ancestor(&self.storage, &hash, &base)?,
+                        None => false,
+                    };
+                    if !merged {
+                        return Err(Error::NotMerged(name));
+                    }
+                }
+
+                self.storage
+                    .delete_ref(&full_name, &Expected::Is(hash.clone()))?;
+                let section = format!("branch.{}.", name);
+                self.storage.modify_config(|config| {
+                    let keys: Vec<String> = config
+                        .iter()
+                        .map(|(key, _)| key)
+                        .filter(|key| key.starts_with(&section))
+                        .cloned()
+                        .collect();
+                    for key in keys {
+                        config.unset(&key);
+                    }
+                })?;
+                writeln!(
+                    self.out,
+                    "Deleted branch {} (was {}).",
+                    name,
+                    hash.short()
+                )?;
+            }
+            BranchAction::Rename { old, new } => {
+                let old = match old {
+                    Some(old) => format!("refs/heads/{}", old),
+                    None => self.current_branch()?,
+                };
+                let new_name = format!("refs/heads/{}", new);
+                check_ref_name(&new_name)?;
+
+                // a branch with no commits yet only lives in HEAD
+                if self.storage.read_ref(&old)?.is_none()
+                    && self.storage.head_ref() == Some(&old)
+                {
+                    self.storage
+                        .set_head_target(Head::Symbolic(new_name))?;
+                } else {
+                    self.storage
+                        .rename_ref(&old, &new_name)?;
+                }
+
+                let old_section = format!("branch.{}.", short_ref_name(&old));
+                let new_section = format!("branch.{}.", new);
+                self.storage.modify_config(|config| {
+                    let moved: Vec<(String, String)> = config
+                        .iter()
+                        .filter(|(key, _)| key.starts_with(&old_section))
+                        .map(|(key, value)| {
+                            (key.to_string(), value.to_string())
+                        })
+                        .collect();
+                    for (key, value) in moved {
+                        config.unset(&key);
+                        config.set(
+                            key.replacen(&old_section, &new_section, 1),
+                            value,
+                        );
+                    }
+                })?;
+            }
+            BranchAction::SetUpstream { name, upstream } => {
+                let name = match name {
+                    Some(name) => name,
+                    None => short_ref_name(&self.current_branch()?).to_string(),
+                };
+                let branch = format!("refs/heads/{}", name);
+                if self
+                    .storage
+                    .read_ref(&branch)?
+                    .is_none()
+                {
+                    return Err(Error::BadRevision(name));
+                }
+                let upstream_ref = format!("refs/heads/{}", upstream);
+                if self
+                    .storage
+                    .read_ref(&upstream_ref)?
+                    .is_none()
+                {
+                    return Err(Error::BadRevision(upstream));
+                }
+
+                // "." as the remote marks a local branch as the upstream
+                self.storage.modify_config(|config| {
+                    config.set(format!("branch.{}.remote", name), ".");
+                    config.set(format!("branch.{}.merge", name), upstream_ref);
+                })?;
+                writeln!(
+                    self.out,
+                    "branch '{}' set up to track '{}'.",
+                    name, upstream
+                )?;
+            }
+            BranchAction::UnsetUpstream { name } => {
+                let name = match name {
+                    Some(name) => name,
+                    None => short_ref_name(&self.current_branch()?).to_string(),
+                };
+                self.storage.modify_config(|config| {
+                    config.unset(&format!("branch.{}.remote", name));
+                    config.unset(&format!("branch.{}.merge", name));
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn checkout(
+        &mut self,
+        rev: &str,
+        new_branch: Option<String>,
+        detach: bool,
+        force: bool,
+    ) -> Result<()> {
+        let fs = self
+            .fs
+            .as_deref()
+            .ok_or(Error::BareRepository)?;
+
+        let hash = self.storage.resolve_rev(rev)?;
+        let Object::Commit {
+            tree_hash, message, ..
+        } = self.storage.read_object(&hash)?
+        else {
+            return Err(Error::BadRevision(rev.to_string()));
+        };
+
+        let branch = format!("refs/heads/{}", rev);
+        let head = match &new_branch {
+            Some(name) => {
+                let name = format!("refs/heads/{}", name);
+                check_ref_name(&name)?;
+                if self.storage.read_ref(&name)?.is_some() {
+                    return Err(Error::RefUpdate(
+                        name,
+                        "already exists".to_string(),
+                    ));
+                }
+                Head::Symbolic(name)
+            }
+            None if !detach
+                && check_ref_name(&branch).is_ok()
+                && self
+                    .storage
+                    .read_ref(&branch)?
+                    .is_some() =>
+            {
+                Head::Symbolic(branch)
+            }
+            None => Head::Detached(hash.clone()),
+        };
+
+        // forced, staying put still throws away local changes
+        let already_on =
+            new_branch.is_none() && *self.storage.head_target() == head;
+        if already_on && !force {
+            writeln!(self.out, "Already on '{}'", rev)?;
+            return Ok(());
+        }
+
+        let target = self.storage.flatten_tree(&tree_hash)?;
+        checkout_files(&mut self.storage, fs, &target, force)?;
+        // a forced switch throws away a merge in progress with its conflicts
+        if let Some(merge_head) = self.storage.read_ref(MERGE_HEAD)? {
+            self.storage
+                .delete_ref(MERGE_HEAD, &Expected::Is(merge_head))?;
+        }
+        if already_on {
+            writeln!(self.out, "Already on '{}'", rev)?;
+            return Ok(());
+        }
+
+        if let Head::Symbolic(name) = &head
+            && new_branch.is_some()
         {
-            fs.create_dir(mount.join("objects").to_str().unwrap())
-                .unwrap();
+            self.storage
+                .create_ref(name, hash.clone())?;
+        }
+        self.storage
+            .set_head_target(head.clone())?;
+
+        match head {
+            Head::Symbolic(name) if new_branch.is_some() => writeln!(
+                self.out,
+                "Switched to a new branch '{}'",
+                short_ref_name(&name)
+            )?,
+            Head::Symbolic(name) => writeln!(
+                self.out,
+                "Switched to branch '{}'",
+                short_ref_name(&name)
+            )?,
+            Head::Detached(hash) => writeln!(
+                self.out,
+                "HEAD is now at {} {}",
+                hash.short(),
+                message.lines().next().unwrap_or("")
+            )?,
         }
+        Ok(())
+    }
 
-        let objects = FsMap::new(mount.join("objects"));
+    fn merge(
+        &mut self,
+        rev: &str,
+        message: Option<String>,
+        no_ff: bool,
+    ) -> Result<()> {
+        let fs = self
+            .fs
+            .as_deref()
+            .ok_or(Error::BareRepository)?;
+        if self
+            .storage
+            .read_ref(MERGE_HEAD)?
+            .is_some()
+        {
+            return Err(Error::Conflict(
+                "You have not concluded your merge (MERGE_HEAD exists).\n\
+                 Please, commit your changes before you merge."
+                    .to_string(),
+            ));
+        }
 
-        Self {
-            index,
-            objects,
-            mount,
-            fs,
+        let theirs = self.storage.resolve_rev(rev)?;
+        let their_files = match self.storage.read_object(&theirs)? {
+            Object::Commit { tree_hash, .. } => {
+                self.storage.flatten_tree(&tree_hash)?
+            }
+            _ => return Err(Error::BadRevision(rev.to_string())),
+        };
+
+        let Some(ours) = self.storage.head() else {
+            // nothing to merge into yet, so take their history as it is
+            checkout_files(&mut self.storage, fs, &their_files, false)?;
+            return self.storage.set_head(theirs);
+        };
+        let bases = merge_bases(&self.storage, &ours, &theirs)?;
+        if bases.contains(&theirs) {
+            writeln!(self.out, "Already up to date.")?;
+            return Ok(());
+        }
+        if bases.contains(&ours) && !no_ff {
+            checkout_files(&mut self.storage, fs, &their_files, false)?;
+            self.storage.set_head(theirs.clone())?;
+            writeln!(
+                self.out,
+                "Updating {}..{}",
+                ours.short(),
+                theirs.short()
+            )?;
+            writeln!(self.out, "Fast-forward")?;
+            return Ok(());
+        }
+        let Some(base) = bases.first() else {
+            return Err(Error::Conflict(
+                "refusing to merge unrelated histories".to_string(),
+            ));
+        };
+
+        // a merge starts from a clean index, and conflict markers must not
+        // overwrite local changes
+        let head_files = self.storage.head_files()?;
+        let mut dirty: Vec<String> =
+            diff_files(&head_files, &self.index_files())
+                .into_iter()
+                .map(|change| change.path().to_string())
+                .collect();
+        let base_files = match self.storage.read_object(base)? {
+            Object::Commit { tree_hash, .. } => {
+                self.storage.flatten_tree(&tree_hash)?
+            }
+            _ => return Err(Error::CorruptObject(base.clone())),
+        };
+        let merge = merge_trees(
+            &mut self.storage,
+            &base_files,
+            &head_files,
+            &their_files,
+            "HEAD",
+            rev,
+        )?;
+        for conflict in &merge.conflicts {
+            if worktree_hash(fs, &conflict.path)? != conflict.ours {
+                dirty.push(conflict.path.clone());
+            }
+        }
+        if !dirty.is_empty() {
+            dirty.sort();
+            dirty.dedup();
+            let mut message = "Your local changes to the following files \
+                               would be overwritten by merge:\n"
+                .to_string();
+            for path in &dirty {
+                message.push_str(&format!("\t{}\n", path));
+            }
+            message.push_str("Please commit your changes before you merge.");
+            return Err(Error::Conflict(message));
+        }
+
+        checkout_files(&mut self.storage, fs, &merge.files, false)?;
+        for path in &merge.merged {
+            writeln!(self.out, "Auto-merging {}", path)?;
         }
+
+        if merge.conflicts.is_empty() {
+            let tree_hash = self.storage.write_tree()?;
+            let message = message.unwrap_or_else(|| {
+                let branch = format!("refs/heads/{}", rev);
+                match self.storage.read_ref(&branch) {
+                    Ok(Some(_)) => format!("Merge branch '{}'\n", rev),
+                    _ => format!("Merge commit '{}'\n", rev),
+                }
+            });
+            let commit = Object::commit(
+                message,
+                tree_hash,
+                vec![ours, theirs],
+                self.storage
+                    .config()
+                    .signature("author"),
+                self.storage
+                    .config()
+                    .signature("committer"),
+            );
+            let commit_hash = self.storage.hash_object(commit)?;
+            self.storage.set_head(commit_hash)?;
+            writeln!(self.out, "Merge made by the 'three-way' strategy.")?;
+            return Ok(());
+        }
+
+        for conflict in &merge.conflicts {
+            write_worktree_content(fs, &conflict.path, &conflict.content)?;
+            let path = &conflict.path;
+            match conflict.kind {
+                ConflictKind::Content => writeln!(
+                    self.out,
+                    "CONFLICT (content): Merge conflict in {}",
+                    path
+                )?,
+                ConflictKind::AddAdd => writeln!(
+                    self.out,
+                    "CONFLICT (add/add): Merge conflict in {}",
+                    path
+                )?,
+                ConflictKind::ModifyDelete => {
+                    let (deleted, modified) = match conflict.ours {
+                        Some(_) => (rev, "HEAD"),
+                        None => ("HEAD", rev),
+                    };
+                    writeln!(
+                        self.out,
+                        "CONFLICT (modify/delete): {} deleted in {} and \
+                         modified in {}. Version {} of {} left in tree.",
+                        path, deleted, modified, modified, path
+                    )?
+                }
+            }
+        }
+        self.storage.modify_index(|index| {
+            for conflict in &merge.conflicts {
+                index.remove(&conflict.path);
+                let stages = [&conflict.base, &conflict.ours, &conflict.theirs];
+                for (stage, hash) in (1..).zip(stages) {
+                    if let Some(hash) = hash {
+                        index.insert(Entry::at_stage(
+                            conflict.path.clone(),
+                            hash.clone(),
+                            stage,
+                        ));
+                    }
+                }
+            }
+        })?;
+        self.storage
+            .update_ref(MERGE_HEAD, theirs, &Expected::Any)?;
+
+        Err(Error::Conflict(
+            "Automatic merge failed; fix conflicts and then commit the result."
+                .to_string(),
+        ))
     }
 
-    pub fn update_index(&mut self, content: Content) {
-        let hash = self.hash_object(Object::blob(content.content));
-        self.index.entries.push(Entry {
-            file_name: content.file_name,
-            blob_hash: BlobHash(hash),
-        });
+    fn log(&mut self, options: &LogOptions) -> Result<()> {
+        let (mut tips, mut excludes) = (Vec::new(), Vec::new());
+        for rev in &options.revs {
+            let (include, exclude) =
+                rev_parse(&self.storage, rev)?.tips(&self.storage)?;
+            tips.extend(include);
+            excludes.extend(exclude);
+        }
+        if options.revs.is_empty() {
+            tips.extend(self.storage.head());
+        }
+        let excluded = ancestors(&self.storage, &excludes)?;
+
+        // the commits to show are picked before any is drawn, so the graph
+        // can join each to its nearest shown ancestors
+        let commits = history(&self.storage, &tips, options.order)?;
+        let mut picked = Vec::new();
+        let mut follow = options.follow.clone();
+        for commit in &commits {
+            if excluded.contains(&commit.hash()) {
+                continue;
+            }
+            let mut changes = Vec::new();
+            if follow.is_some() || options.name_status {
+                changes = self.commit_changes(commit, &options.renames)?;
+            }
+            if let Some(path) = &mut follow {
+                changes.retain(|change| change.path() == path);
+                let Some(change) = changes.first() else {
+                    continue;
+                };
+                // older commits know the file by the name it came from
+                if change.similarity.is_some() {
+                    *path = change.old_path.clone();
+                }
+            }
+
+            // the graph still needs the commits past the limit to know
+            // where the last shown ones lead
+            if !options.graph
+                && options
+                    .max_count
+                    .is_some_and(|max| picked.len() >= max)
+            {
+                break;
+            }
+            if !options.matches(commit) {
+                continue;
+            }
+            picked.push((commit, changes));
+        }
+
+        let parents = match options.graph {
+            true => {
+                let shown = picked
+                    .iter()
+                    .map(|(commit, _)| commit.hash())
+                    .collect();
+                shown_parents(&commits, &shown)
+            }
+            false => HashMap::new(),
+        };
+        let mut graph = Graph::new();
+        let count = options
+            .max_count
+            .unwrap_or(picked.len());
+        for (commit, changes) in picked.into_iter().take(count) {
+            let mut lines = format_commit(commit, options.oneline);
+            if options.name_status {
+                lines.extend(changes.iter().map(name_status));
+                if !options.oneline && !changes.is_empty() {
+                    lines.push(String::new());
+                }
+            }
+            if !options.graph {
+                for line in lines {
+                    writeln!(self.out, "{}", line)?;
+                }
+                continue;
+            }
+
+            let hash = commit.hash();
+            let (first, transitions, rest) = graph.next(&hash, &parents[&hash]);
+            let prefixes = std::iter::once(first).chain(transitions);
+            let mut prefixes = prefixes.collect::<Vec<_>>().into_iter();
+            let mut lines = lines.into_iter();
+            loop {
+                let (prefix, line) = match (prefixes.next(), lines.next()) {
+                    (None, None) => break,
+                    (Some(prefix), line) => (prefix, line.unwrap_or_default()),
+                    (None, Some(line)) => (rest.clone(), line),
+                };
+                writeln!(
+                    self.out,
+                    "{}",
+                    format!("{}{}", prefix, line).trim_end()
+                )?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn hash_object(&mut self, object: Object) -> Hash {
-        let hash = object.hash();
-        self.objects.insert(
-            hash.clone(),
-            serde_json::to_string(&object).unwrap(),
-            &mut self.fs,
-        );
-        hash
+    fn diff(
+        &mut self,
+        cached: bool,
+        revs: &[String],
+        format: DiffFormat,
+        options: &DiffOptions,
+    ) -> Result<()> {
+        let index = self.index_files();
+        // an unmerged path has no one staged version to compare, so where
+        // the index is a side (no revs, or cached) it is only named, like
+        // git does
+        let unmerged: Vec<String> = match cached || revs.is_empty() {
+            true => self
+                .storage
+                .index()
+                .unmerged()
+                .into_iter()
+                .map(|unmerged| unmerged.path)
+                .collect(),
+            false => Vec::new(),
+        };
+        let without_unmerged = |mut files: BTreeMap<String, Hash>| {
+            for path in &unmerged {
+                files.remove(path);
+            }
+            files
+        };
+
+        // the new side is read from the worktree unless comparing the
+        // index or commits
+        let hashes = self.diff_revs(revs)?;
+        let (changes, from_worktree) = match (hashes.as_slice(), cached) {
+            ([old, new], false) => {
+                let old = self.rev_tree(old)?;
+                let new = self.rev_tree(new)?;
+                (diff_trees(&self.storage, Some(&old), Some(&new))?, false)
+            }
+            ([], true) => {
+                let old = without_unmerged(self.storage.head_files()?);
+                (diff_files(&old, &index), false)
+            }
+            ([rev], true) => {
+                let tree = self.rev_tree(rev)?;
+                let old = without_unmerged(self.storage.flatten_tree(&tree)?);
+                (diff_files(&old, &index), false)
+            }
+            ([rev], false) => {
+                let tree = self.rev_tree(rev)?;
+                let old = self.storage.flatten_tree(&tree)?;
+                let paths = old.keys().chain(index.keys());
+                let worktree = self.worktree_files_at(paths)?;
+                (diff_files(&old, &worktree), true)
+            }
+            ([], false) => {
+                let worktree = self.worktree_files_at(index.keys())?;
+                (diff_files(&index, &worktree), true)
+            }
+            _ => {
+                return Err(Error::BadRevision(revs.join(" ")));
+            }
+        };
+        let changes =
+            detect_renames(changes, &options.renames, |change, new| {
+                self.side_content(change, new, from_worktree)
+            })?;
+
+        for path in &unmerged {
+            match format {
+                DiffFormat::NameOnly => writeln!(self.out, "{}", path)?,
+                DiffFormat::NameStatus => writeln!(self.out, "U\t{}", path)?,
+                DiffFormat::Patch => {
+                    writeln!(self.out, "* Unmerged path {}", path)?
+                }
+                DiffFormat::Stat => {}
+            }
+        }
+        match format {
+            DiffFormat::NameOnly => {
+                for change in &changes {
+                    writeln!(self.out, "{}", change.path())?;
+                }
+            }
+            DiffFormat::NameStatus => {
+                for change in &changes {
+                    writeln!(self.out, "{}", name_status(change))?;
+                }
+            }
+            DiffFormat::Stat => {
+                let mut stats = Vec::new();
+                for change in &changes {
+                    let (old, new) =
+                        self.change_contents(change, from_worktree)?;
+                    stats.push(FileStat::new(
+                        stat_path(change),
+                        &old,
+                        &new,
+                        options,
+                    ));
+                }
+                write_stat(&mut self.out, &stats)?;
+            }
+            DiffFormat::Patch => {
+                for change in &changes {
+                    let (old, new) =
+                        self.change_contents(change, from_worktree)?;
+                    write_patch(&mut self.out, change, &old, &new, options)?;
+                }
+            }
+        }
+        Ok(())
     }
-}
 
-impl Storage for JsonStorage {
-    fn index(&self) -> &Index {
-        &self.index
+    // what `commit` changed against its first parent, renames paired up
+    fn commit_changes(
+        &self,
+        commit: &Object,
+        renames: &RenameOptions,
+    ) -> Result<Vec<FileChange>> {
+        let Object::Commit {
+            tree_hash, parents, ..
+        } = commit
+        else {
+            return Ok(Vec::new());
+        };
+        let parent_tree = match parents.first() {
+            Some(parent) => match self.storage.read_object(parent)? {
+                Object::Commit { tree_hash, .. } => Some(tree_hash),
+                _ => return Err(Error::CorruptObject(parent.clone())),
+            },
+            None => None,
+        };
+
+        let changes =
+            diff_trees(&self.storage, parent_tree.as_ref(), Some(tree_hash))?;
+        detect_renames(changes, renames, |change, new| {
+            self.side_content(change, new, false)
+        })
     }
 
-    fn objects(&self) -> &Vec<Object> {
-        &self.objects
+    // the staged files by path, leaving out unmerged ones
+    fn index_files(&self) -> BTreeMap<String, Hash> {
+        self.storage
+            .index()
+            .entries()
+            .iter()
+            .filter(|entry| entry.stage() == 0)
+            .map(|entry| {
+                (entry.file_name().to_string(), entry.blob_hash().clone())
+            })
+            .collect()
     }
 
-    fn modify_index(&mut self, modifier: Box<dyn FnOnce(&mut Index)>) {
-        modifier(&mut self.index);
+    // the commits `revs` name for diff: "A..B" stands for A and B, and
+    // "A...B" for their merge base and B
+    fn diff_revs(&self, revs: &[String]) -> Result<Vec<Hash>> {
+        let mut hashes = Vec::new();
+        for rev in revs {
+            match rev_parse(&self.storage, rev)? {
+                RevSpec::Single(hash) => hashes.push(hash),
+                RevSpec::Range(a, b) => hashes.extend([a, b]),
+                RevSpec::Symmetric(a, b) => {
+                    let base = merge_bases(&self.storage, &a, &b)?
+                        .into_iter()
+                        .next()
+                        .ok_or(Error::NoMergeBase)?;
+                    hashes.extend([base, b]);
+                }
+                RevSpec::Exclude(_) => {
+                    return Err(Error::BadRevision(rev.clone()));
+                }
+            }
+        }
+        Ok(hashes)
     }
 
-    fn add_entry(&mut self, entry: Entry) {
-        self.index.entries.push(entry);
+    // the root tree of the commit `hash`, or `hash` itself for a tree
+    fn rev_tree(&self, hash: &Hash) -> Result<Hash> {
+        match self.storage.read_object(hash)? {
+            Object::Commit { tree_hash, .. } => Ok(tree_hash),
+            Object::Tree { .. } => Ok(hash.clone()),
+            _ => Err(Error::BadRevision(hash.to_hex())),
+        }
     }
 
-    fn hash_object(&mut self, object: Object) -> Hash {
-        let hash = object.hash();
-        self.objects.push(object);
-        hash
+    // the blob ids of those of `paths` that are files in the worktree
+    fn worktree_files_at<'a>(
+        &self,
+        paths: impl Iterator<Item = &'a String>,
+    ) -> Result<BTreeMap<String, Hash>> {
+        let fs = self.worktree()?;
+        let mut files = BTreeMap::new();
+        for path in paths {
+            if let Some(hash) = worktree_hash(fs, path)? {
+                files.insert(path.clone(), hash);
+            }
+        }
+        Ok(files)
     }
 
-    fn update_index(&mut self, content: Content) {
-        let blob_hash = self.hash_object(Object::blob(content.content));
+    // both sides of `change`, empty where the file is missing; the new side
+    // is read from the worktree when `from_worktree`
+    fn change_contents(
+        &self,
+        change: &FileChange,
+        from_worktree: bool,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok((
+            self.side_content(change, false, from_worktree)?,
+            self.side_content(change, true, from_worktree)?,
+        ))
+    }
 
-        self.index.entries.push(Entry {
-            blob_hash: BlobHash(blob_hash),
-            file_name: content.file_name,
-        });
+    // one side of `change`, the new one when `new_side`
+    fn side_content(
+        &self,
+        change: &FileChange,
+        new_side: bool,
+        from_worktree: bool,
+    ) -> Result<Vec<u8>> {
+        if !new_side {
+            return blob_content(&self.storage, change.old_hash.as_ref());
+        }
+        match (&change.new_hash, from_worktree) {
+            (Some(_), true) => {
+                let mut content = Vec::new();
+                self.worktree()?
+                    .open_file(&format!("/{}", change.new_path))?
+                    .read_to_end(&mut content)?;
+                Ok(content)
+            }
+            (hash, _) => blob_content(&self.storage, hash.as_ref()),
+        }
+    }
+
+    // the full ref name of the checked out branch
+    fn current_branch(&self) -> Result<String> {
+        self.storage
+            .head_ref()
+            .map(str::to_string)
+            .ok_or_else(|| Error::BadRevision("HEAD".to_string()))
+    }
+
+    // the ref the branch `name` tracks, from its "branch.<name>.merge"
+    // setting
+    fn upstream(&self, name: &str) -> Option<String> {
+        self.storage
+            .config()
+            .get(&format!("branch.{}.merge", name))
+            .map(str::to_string)
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Index {
-    entries: Vec<Entry>,
+    fn worktree(&self) -> Result<&dyn FileSystem> {
+        self.fs
+            .as_deref()
+            .ok_or(Error::BareRepository)
+    }
 }
 
-impl Default for Index {
-    fn default() -> Self {
-        Self {
-            entries: Vec::new(),
-        }
+fn print_commit(out: &mut dyn Write, commit: &Object) -> Result<()> {
+    for line in format_commit(commit, false) {
+        writeln!(out, "{}", line)?;
     }
+    Ok(())
 }
 
-impl Index {
-    pub fn new() -> Self {
-        Self {
-            entries: Vec::new(),
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use vfs::MemoryFS;
+
+    use super::*;
+    use crate::{JsonStorage, LogOrder};
+
+    // command output, shared with the runner writing it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Entry {
-    file_name: String,
-    blob_hash: BlobHash,
-}
+    // a runner on an in-memory repository and worktree
+    struct Repo {
+        runner: Runner<JsonStorage>,
+        output: Output,
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Object {
-    Blob {
-        hash: BlobHash,
-        content: String,
-    },
-    Tree {
-        hash: Hash,
-        contents: Vec<(PathBuf, ObjectMode, Hash)>,
-    },
-    Commit {
-        hash: Hash,
-        message: String,
-        tree_hash: Hash,
-        parent_commit: Option<Hash>,
-    },
-}
+    fn repo() -> Repo {
+        let mut runner =
+            Runner::new(JsonStorage::new(), Box::new(MemoryFS::new()));
+        let output = Output::default();
+        runner.set_output(Box::new(output.clone()));
+        Repo { runner, output }
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ObjectMode {
-    Blob,
-    Tree,
-    Commit,
-}
+    impl Repo {
+        fn fs(&self) -> &dyn FileSystem {
+            self.runner.fs.as_deref().unwrap()
+        }
+
+        fn write(&self, path: &str, content: &str) {
+            write_worktree_content(self.fs(), path, content.as_bytes())
+                .unwrap();
+        }
+
+        fn remove(&self, path: &str) {
+            self.fs()
+                .remove_file(&format!("/{}", path))
+                .unwrap();
+        }
+
+        // what `cmd` printed
+        fn run(&mut self, cmd: Cmd) -> Result<String> {
+            self.output.0.borrow_mut().clear();
+            self.runner.run(cmd)?;
+            Ok(String::from_utf8(self.output.0.take()).unwrap())
+        }
+
+        // stage everything and commit it
+        fn commit(&mut self, message: &str) {
+            self.run(Cmd::AddAll).unwrap();
+            self.run(Cmd::Commit {
+                message: message.to_string(),
+            })
+            .unwrap();
+        }
+
+        fn read(&self, path: &str) -> Option<String> {
+            let path = format!("/{}", path);
+            if !self.fs().exists(&path).unwrap() {
+                return None;
+            }
+            let mut content = String::new();
+            self.fs()
+                .open_file(&path)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            Some(content)
+        }
+
+        fn porcelain(&mut self) -> String {
+            self.run(Cmd::Status {
+                format: StatusFormat::Porcelain,
+            })
+            .unwrap()
+        }
+
+        // the subjects `log` lists from `revs`, children before parents
+        fn subjects(&mut self, revs: &[&str]) -> Vec<String> {
+            let options = LogOptions {
+                revs: revs
+                    .iter()
+                    .map(|rev| rev.to_string())
+                    .collect(),
+                oneline: true,
+                order: LogOrder::Topo,
+                ..Default::default()
+            };
+            self.run(Cmd::Log { options })
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    line.split_once(' ')
+                        .unwrap()
+                        .1
+                        .to_string()
+                })
+                .collect()
+        }
+
+        fn branch(&mut self, action: BranchAction) -> String {
+            self.run(Cmd::Branch { action })
+                .unwrap()
+        }
 
-impl Object {
-    fn hash(&self) -> Hash {
-        match self {
-            Object::Blob { hash, .. } => hash.clone().0,
-            Object::Tree { hash, .. } => hash.clone(),
-            Object::Commit { hash, .. } => hash.clone(),
+        fn checkout(&mut self, rev: &str, new_branch: Option<&str>) -> String {
+            self.run(Cmd::Checkout {
+                rev: rev.to_string(),
+                new_branch: new_branch.map(str::to_string),
+                detach: false,
+                force: false,
+            })
+            .unwrap()
+        }
+
+        fn merge(&mut self, rev: &str) -> Result<String> {
+            self.run(Cmd::Merge {
+                rev: rev.to_string(),
+                message: None,
+                no_ff: false,
+            })
+        }
+
+        fn parents(&self, rev: &str) -> Vec<Hash> {
+            let hash = self
+                .runner
+                .storage
+                .resolve_rev(rev)
+                .unwrap();
+            match self
+                .runner
+                .storage
+                .read_object(&hash)
+                .unwrap()
+            {
+                Object::Commit { parents, .. } => parents,
+                _ => panic!("{} is no commit", rev),
+            }
+        }
+
+        fn diff(
+            &mut self,
+            cached: bool,
+            revs: &[&str],
+            format: DiffFormat,
+        ) -> String {
+            self.run(Cmd::Diff {
+                cached,
+                revs: revs
+                    .iter()
+                    .map(|rev| rev.to_string())
+                    .collect(),
+                format,
+                options: DiffOptions::default(),
+            })
+            .unwrap()
         }
     }
 
-    fn blob(content: String) -> Self {
-        let hash: Hash = Hash(Sha1::digest(content.clone()).to_vec());
+    const LONG: &str = "a file long enough to be paired as a rename\n";
+
+    // c1 and then c2, which modifies a.txt, adds c.txt, deletes gone.txt
+    // and renames old.txt to new.txt
+    fn two_commits() -> Repo {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n2\n3\n");
+        repo.write("gone.txt", "g\n");
+        repo.write("old.txt", LONG);
+        repo.commit("c1");
 
-        Object::Blob {
-            hash: BlobHash(hash),
-            content,
+        repo.write("a.txt", "1\n2\nthree\n");
+        repo.write("c.txt", "c\n");
+        repo.remove("gone.txt");
+        repo.remove("old.txt");
+        repo.write("new.txt", LONG);
+        repo.commit("c2");
+        repo
+    }
+
+    #[test]
+    fn diff_between_two_revisions() {
+        let mut repo = two_commits();
+        for revs in [&["HEAD~1", "HEAD"][..], &["HEAD~1..HEAD"]] {
+            assert_eq!(
+                repo.diff(false, revs, DiffFormat::NameStatus),
+                "M\ta.txt\nA\tc.txt\nD\tgone.txt\nR100\told.txt\tnew.txt\n"
+            );
         }
+        assert_eq!(
+            repo.diff(false, &["HEAD~1", "HEAD"], DiffFormat::NameOnly),
+            "a.txt\nc.txt\ngone.txt\nnew.txt\n"
+        );
+        assert_eq!(
+            repo.diff(false, &["HEAD~1", "HEAD"], DiffFormat::Stat),
+            " a.txt              | 2 +-\n \
+             c.txt              | 1 +\n \
+             gone.txt           | 1 -\n \
+             old.txt => new.txt | 0\n \
+             4 files changed, 2 insertions(+), 2 deletions(-)\n"
+        );
+
+        let patch = repo.diff(false, &["HEAD~1", "HEAD"], DiffFormat::Patch);
+        let lines: Vec<&str> = patch
+            .lines()
+            .filter(|line| !line.starts_with("index "))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "diff --git a/a.txt b/a.txt",
+                "--- a/a.txt",
+                "+++ b/a.txt",
+                "@@ -1,3 +1,3 @@",
+                " 1",
+                " 2",
+                "-3",
+                "+three",
+                "diff --git a/c.txt b/c.txt",
+                "new file mode 100644",
+                "--- /dev/null",
+                "+++ b/c.txt",
+                "@@ -0,0 +1 @@",
+                "+c",
+                "diff --git a/gone.txt b/gone.txt",
+                "deleted file mode 100644",
+                "--- a/gone.txt",
+                "+++ /dev/null",
+                "@@ -1 +0,0 @@",
+                "-g",
+                "diff --git a/old.txt b/new.txt",
+                "similarity index 100%",
+                "rename from old.txt",
+                "rename to new.txt",
+            ]
+        );
     }
 
-    fn tree(contents: Vec<(PathBuf, Hash)>) -> Self {
-        let hash: Hash = Hash(
-            Sha1::digest(format!(
-                "{:?}",
-                contents
-                    .iter()
-                    .map(|c| c.1.clone())
-                    .collect::<Vec<_>>()
-            ))
-            .to_vec(),
+    #[test]
+    fn diff_of_the_index_and_the_worktree() {
+        let mut repo = two_commits();
+        repo.write("a.txt", "1\n2\nstaged\n");
+        repo.run(Cmd::Add {
+            file_name: "/a.txt".to_string(),
+        })
+        .unwrap();
+        repo.write("a.txt", "1\n2\nlocal\n");
+        repo.write("c.txt", "c\nd\n");
+        repo.write("untracked.txt", "u\n");
+
+        // the index against HEAD, or against another commit
+        assert_eq!(repo.diff(true, &[], DiffFormat::NameStatus), "M\ta.txt\n");
+        assert_eq!(
+            repo.diff(true, &["HEAD~1"], DiffFormat::NameStatus),
+            "M\ta.txt\nA\tc.txt\nD\tgone.txt\nR100\told.txt\tnew.txt\n"
         );
 
-        Object::Tree {
-            hash: hash.clone(),
-            contents: contents
-                .iter()
-                .map(|c| (c.0.clone(), ObjectMode::Blob, c.1.clone()))
-                .collect(),
+        // the worktree against the index, leaving out untracked files
+        assert_eq!(
+            repo.diff(false, &[], DiffFormat::NameStatus),
+            "M\ta.txt\nM\tc.txt\n"
+        );
+        let patch = repo.diff(false, &[], DiffFormat::Patch);
+        assert!(patch.contains("-staged\n+local\n"));
+        assert_eq!(
+            repo.diff(false, &[], DiffFormat::Stat),
+            " a.txt | 2 +-\n \
+             c.txt | 1 +\n \
+             2 files changed, 2 insertions(+), 1 deletion(-)\n"
+        );
+
+        // and against a commit
+        let patch = repo.diff(false, &["HEAD"], DiffFormat::Patch);
+        assert!(patch.contains("-three\n+local\n"));
+        assert!(!patch.contains("untracked"));
+    }
+
+    #[test]
+    fn commits_make_a_history() {
+        let mut repo = repo();
+        assert_eq!(repo.porcelain(), "");
+        for (i, content) in ["1\n", "2\n", "3\n"]
+            .into_iter()
+            .enumerate()
+        {
+            repo.write("a.txt", content);
+            repo.write(&format!("dir/{}.txt", i), content);
+            repo.commit(&format!("c{}", i + 1));
         }
+        assert_eq!(repo.porcelain(), "");
+        assert_eq!(repo.subjects(&[]), ["c3", "c2", "c1"]);
+        assert_eq!(repo.subjects(&["HEAD~2..HEAD"]), ["c3", "c2"]);
+
+        let show = |repo: &mut Repo, object: &str| {
+            repo.run(Cmd::Show {
+                object: Some(object.to_string()),
+            })
+            .unwrap()
+        };
+        assert_eq!(show(&mut repo, "HEAD~1:a.txt"), "2\n");
+        assert_eq!(
+            show(&mut repo, "HEAD:dir"),
+            "tree HEAD:dir\n\n0.txt\n1.txt\n2.txt\n"
+        );
+
+        let head = repo.runner.storage.head().unwrap();
+        let parsed = repo
+            .run(Cmd::RevParse {
+                revs: vec!["main".to_string()],
+            })
+            .unwrap();
+        assert_eq!(parsed, format!("{}\n", head));
     }
 
-    fn commit(message: String, tree_hash: Hash) -> Self {
-        let hash: Hash = Hash(Sha1::digest(message.clone()).to_vec());
+    #[test]
+    fn branches_are_listed_renamed_and_deleted() {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n");
+        repo.commit("c1");
+        repo.branch(BranchAction::Create {
+            name: "topic".to_string(),
+            start: None,
+            force: false,
+        });
+        let list = BranchAction::List { verbose: 0 };
+        assert_eq!(repo.branch(list), "* main\n  topic\n");
+
+        // the current branch too, with HEAD following it
+        repo.branch(BranchAction::Rename {
+            old: None,
+            new: "trunk".to_string(),
+        });
+        repo.branch(BranchAction::Rename {
+            old: Some("topic".to_string()),
+            new: "feature".to_string(),
+        });
+        let list = BranchAction::List { verbose: 0 };
+        assert_eq!(repo.branch(list), "  feature\n* trunk\n");
+        assert_eq!(repo.runner.storage.head_ref(), Some("refs/heads/trunk"));
 
-        Object::Commit {
-            hash: hash.clone(),
-            message,
-            tree_hash,
-            parent_commit: None,
-        }
+        let head = repo.runner.storage.head().unwrap();
+        let deleted = repo.branch(BranchAction::Delete {
+            name: "feature".to_string(),
+            force: false,
+        });
+        assert_eq!(
+            deleted,
+            format!("Deleted branch feature (was {}).\n", head.short())
+        );
+        let list = BranchAction::List { verbose: 0 };
+        assert_eq!(repo.branch(list), "* trunk\n");
     }
-}
 
-pub enum Cmd {
-    Add { file_name: String },
-    Commit { message: String },
-}
+    #[test]
+    fn checkout_switches_the_worktree_and_head() {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n");
+        repo.commit("c1");
+        assert_eq!(
+            repo.checkout("HEAD", Some("topic")),
+            "Switched to a new branch 'topic'\n"
+        );
+        repo.write("a.txt", "2\n");
+        repo.write("b.txt", "b\n");
+        repo.commit("c2");
 
-pub struct Runner {
-    // pub storage: Box<dyn Storage>,
-    pub storage: FsMapedJson,
-    fs: Box<dyn FileSystem>,
-}
+        assert_eq!(repo.checkout("main", None), "Switched to branch 'main'\n");
+        assert_eq!(repo.read("a.txt").as_deref(), Some("1\n"));
+        assert_eq!(repo.read("b.txt"), None);
+        assert_eq!(repo.porcelain(), "");
+        assert_eq!(repo.checkout("main", None), "Already on 'main'\n");
 
-impl Runner {
-    pub fn new(storage: FsMapedJson, fs: Box<dyn FileSystem>) -> Self {
-        Self { storage, fs }
+        let topic = repo
+            .runner
+            .storage
+            .resolve_rev("topic")
+            .unwrap();
+        assert_eq!(
+            repo.checkout(&topic.to_hex(), None),
+            format!("HEAD is now at {} c2\n", topic.short())
+        );
+        assert_eq!(repo.runner.storage.head_ref(), None);
+        assert_eq!(repo.read("b.txt").as_deref(), Some("b\n"));
+
+        // local changes in the way stop the switch
+        repo.write("a.txt", "local\n");
+        let refused = repo.run(Cmd::Checkout {
+            rev: "main".to_string(),
+            new_branch: None,
+            detach: false,
+            force: false,
+        });
+        assert!(matches!(refused, Err(Error::Conflict(_))));
+        assert_eq!(repo.runner.storage.head(), Some(topic));
     }
 
-    pub fn run(&mut self, cmd: Cmd) {
-        match cmd {
-            Cmd::Add { file_name } => {
-                if let Ok(mut file) = self.fs.open_file(&file_name) {
-                    let mut content = String::new();
-                    file.read_to_string(&mut content)
-                        .unwrap();
-                    println!("{:?}", content);
+    #[test]
+    fn merges_fast_forward_or_make_a_merge_commit() {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n2\n3\n");
+        repo.commit("c1");
+        repo.checkout("HEAD", Some("topic"));
+        repo.write("b.txt", "b\n");
+        repo.commit("t1");
+        repo.checkout("main", None);
 
-                    self.storage
-                        .update_index(Content::new(file_name, content));
-                } else {
-                    eprintln!("File not found");
-                }
-            }
-            Cmd::Commit { message } => {
-                // let tree: Vec<(PathBuf, Hash)> = self
-                //     .storage
-                //     .index()
-                //     .entries
-                //     .iter()
-                //     .map(|entry| {
-                //         (
-                //             PathBuf::from(&entry.file_name),
-                //             entry.blob_hash.0.clone(),
-                //         )
-                //     })
-                //     .collect();
+        let old = repo.runner.storage.head().unwrap();
+        let topic = repo
+            .runner
+            .storage
+            .resolve_rev("topic")
+            .unwrap();
+        assert_eq!(
+            repo.merge("topic").unwrap(),
+            format!(
+                "Updating {}..{}\nFast-forward\n",
+                old.short(),
+                topic.short()
+            )
+        );
+        assert_eq!(repo.runner.storage.head(), Some(topic));
+        assert_eq!(repo.merge("topic").unwrap(), "Already up to date.\n");
 
-                // let tree = Object::tree(tree);
-                // self.storage.hash_object(tree.clone());
+        // both sides change a.txt, in lines far enough apart
+        repo.write("a.txt", "one\n2\n3\n");
+        repo.commit("c2");
+        repo.checkout("topic", None);
+        repo.write("a.txt", "1\n2\nthree\n");
+        repo.commit("t2");
+        repo.checkout("main", None);
+        assert_eq!(
+            repo.merge("topic").unwrap(),
+            "Auto-merging a.txt\n\
+             Merge made by the 'three-way' strategy.\n"
+        );
+        assert_eq!(repo.read("a.txt").as_deref(), Some("one\n2\nthree\n"));
+        assert_eq!(repo.parents("HEAD").len(), 2);
+        assert_eq!(
+            repo.subjects(&[]),
+            ["Merge branch 'topic'", "c2", "t2", "t1", "c1"]
+        );
+        assert_eq!(repo.porcelain(), "");
+    }
 
-                // let commit = Object::commit(message, tree.hash());
-                // self.storage.hash_object(commit);
-            }
-        }
+    #[test]
+    fn a_conflicting_merge_waits_for_a_commit() {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n");
+        repo.commit("c1");
+        repo.checkout("HEAD", Some("topic"));
+        repo.write("a.txt", "theirs\n");
+        repo.commit("t1");
+        repo.checkout("main", None);
+        repo.write("a.txt", "ours\n");
+        repo.commit("c2");
+
+        let stopped = repo.merge("topic");
+        assert!(matches!(stopped, Err(Error::Conflict(_))));
+        assert_eq!(
+            repo.read("a.txt").as_deref(),
+            Some("<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> topic\n")
+        );
+        assert_eq!(repo.porcelain(), "UU a.txt\n");
+        assert_eq!(repo.diff(false, &[], DiffFormat::NameStatus), "U\ta.txt\n");
+        // no commit with conflicts left in the index
+        let early = repo.run(Cmd::Commit {
+            message: "too early".to_string(),
+        });
+        assert!(matches!(early, Err(Error::Unmerged(_))));
+
+        repo.write("a.txt", "both\n");
+        repo.commit("merged");
+        let topic = repo
+            .runner
+            .storage
+            .resolve_rev("topic")
+            .unwrap();
+        assert_eq!(repo.parents("HEAD")[1], topic);
+        assert_eq!(
+            repo.runner
+                .storage
+                .read_ref(MERGE_HEAD)
+                .unwrap(),
+            None
+        );
+        assert_eq!(repo.porcelain(), "");
     }
-}
 
-// impl Runner {
-//     pub fn new(storage: Box<dyn Storage>, fs: Box<dyn FileSystem>) -> Self {
-//         Self { storage, fs }
-//     }
-
-//     pub fn run(&mut self, cmd: Cmd) {
-//         match cmd {
-//             Cmd::Add { file_name } => {
-//                 if let Ok(mut file) = self.fs.open_file(&file_name) {
-//                     let mut content = String::new();
-//                     file.read_to_string(&mut content)
-//                         .unwrap();
-//                     println!("{:?}", content);
-
-//                     self.storage
-//                         .update_index(Content::new(file_name, content));
-//                 } else {
-//                     eprintln!("File not found");
-//                 }
-//             }
-//             Cmd::Commit { message } => {
-//                 let tree: Vec<(PathBuf, Hash)> = self
-//                     .storage
-//                     .index()
-//                     .entries
-//                     .iter()
-//                     .map(|entry| {
-//                         (
-//                             PathBuf::from(&entry.file_name),
-//                             entry.blob_hash.0.clone(),
-//                         )
-//                     })
-//                     .collect();
-
-//                 let tree = Object::tree(tree);
-//                 self.storage.hash_object(tree.clone());
-
-//                 let commit = Object::commit(message, tree.hash());
-//                 self.storage.hash_object(commit);
-//             }
-//         }
-//     }
-// }
+    #[test]
+    fn merge_base_finds_the_fork_point() {
+        let mut repo = repo();
+        repo.write("a.txt", "1\n");
+        repo.commit("c1");
+        let fork = repo.runner.storage.head().unwrap();
+        repo.checkout("HEAD", Some("topic"));
+        repo.write("b.txt", "b\n");
+        repo.commit("t1");
+        repo.checkout("main", None);
+        repo.write("a.txt", "2\n");
+        repo.commit("c2");
+
+        let base = repo
+            .run(Cmd::MergeBase {
+                commits: vec!["main".to_string(), "topic".to_string()],
+                all: false,
+                octopus: false,
+            })
+            .unwrap();
+        assert_eq!(base, format!("{}\n", fork));
+
+        let is_ancestor = |repo: &mut Repo, ancestor: &str, commit: &str| {
+            repo.run(Cmd::IsAncestor {
+                ancestor: ancestor.to_string(),
+                commit: commit.to_string(),
+            })
+        };
+        assert!(is_ancestor(&mut repo, "main~1", "topic").is_ok());
+        assert!(matches!(
+            is_ancestor(&mut repo, "main", "topic"),
+            Err(Error::NotAncestor(..))
+        ));
+    }
+
+    #[test]
+    fn a_bare_runner_refuses_worktree_commands() {
+        let mut runner = Runner::bare(JsonStorage::new());
+        runner.set_output(Box::new(Output::default()));
+        let status = runner.run(Cmd::Status {
+            format: StatusFormat::Porcelain,
+        });
+        assert!(matches!(status, Err(Error::BareRepository)));
+        assert!(matches!(
+            runner.run(Cmd::AddAll),
+            Err(Error::BareRepository)
+        ));
+
+        // settings need no worktree
+        runner
+            .run(Cmd::Config {
+                key: "user.name".to_string(),
+                value: Some("A".to_string()),
+            })
+            .unwrap();
+        assert_eq!(runner.storage.config().get("user.name"), Some("A"));
+    }
+}
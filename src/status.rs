@@ -0,0 +1,530 @@
+use std::{collections::BTreeMap, io::Write};
+
+use serde::Serialize;
+use vfs::{FileSystem, VfsFileType};
+
+use crate::{
+    Hash, MOUNT, Object, RenameOptions, Result, Storage, Unmerged,
+    blob_content, detect_renames, diff_files,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    Copied,
+}
+
+impl Change {
+    // the one-letter code porcelain output uses
+    pub fn code(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Modified => 'M',
+            Change::Deleted => 'D',
+            Change::Renamed => 'R',
+            Change::Copied => 'C',
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Change::Added => "new file:",
+            Change::Modified => "modified:",
+            Change::Deleted => "deleted:",
+            Change::Renamed => "renamed:",
+            Change::Copied => "copied:",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileStatus {
+    pub path: String,
+    pub change: Change,
+    // where a renamed or copied file came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+}
+
+impl FileStatus {
+    // "old -> new" for a rename or copy, the path otherwise
+    fn display_path(&self) -> String {
+        match &self.old_path {
+            Some(old_path) => format!("{} -> {}", old_path, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+pub enum StatusFormat {
+    // the sectioned, human-readable report
+    Long,
+    // one stable "XY path" line per file, like `git status --porcelain`
+    Porcelain,
+    Json,
+}
+
+// how the worktree and index differ from HEAD and from each other
+#[derive(Debug, Serialize)]
+pub struct Status {
+    // short branch name, None when HEAD is detached
+    pub branch: Option<String>,
+    pub head: Option<Hash>,
+    // index against HEAD, with renames paired up
+    pub staged: Vec<FileStatus>,
+    // paths with merge conflicts left to resolve
+    pub unmerged: Vec<Unmerged>,
+    // worktree against index
+    pub unstaged: Vec<FileStatus>,
+    // worktree files the index does not know about
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    pub fn new(
+        storage: &impl Storage,
+        worktree: &dyn FileSystem,
+    ) -> Result<Self> {
+        // unmerged paths are listed on their own rather than as changes
+        let unmerged = storage.index().unmerged();
+        let mut head_files = storage.head_files()?;
+        for file in &unmerged {
+            head_files.remove(&file.path);
+        }
+        let index_files: BTreeMap<&str, &Hash> = storage
+            .index()
+            .entries()
+            .iter()
+            .filter(|entry| entry.stage() == 0)
+            .map(|entry| (entry.file_name(), entry.blob_hash()))
+            .collect();
+
+        let index_map: BTreeMap<String, Hash> = index_files
+            .iter()
+            .map(|(path, hash)| (path.to_string(), (*hash).clone()))
+            .collect();
+        let staged = detect_renames(
+            diff_files(&head_files, &index_map),
+            &RenameOptions::default(),
+            |change, new_side| {
+                let hash = match new_side {
+                    true => &change.new_hash,
+                    false => &change.old_hash,
+                };
+                blob_content(storage, hash.as_ref())
+            },
+        )?
+        .into_iter()
+        .map(|change| FileStatus {
+            path: change.path().to_string(),
+            change: change.change,
+            old_path: change
+                .similarity
+                .map(|_| change.old_path),
+        })
+        .collect();
+
+        let mut unstaged = Vec::new();
+        let mut untracked = Vec::new();
+        let worktree_files = worktree_files(worktree, "")?;
+        for path in &worktree_files {
+            if unmerged
+                .iter()
+                .any(|file| file.path == *path)
+            {
+                continue;
+            }
+            let Some(hash) = index_files.get(path.as_str()) else {
+                untracked.push(path.clone());
+                continue;
+            };
+
+            let mut content = Vec::new();
+            worktree
+                .open_file(&format!("/{}", path))?
+                .read_to_end(&mut content)?;
+            if Object::blob(content).hash() != **hash {
+                unstaged.push(FileStatus {
+                    path: path.clone(),
+                    change: Change::Modified,
+                    old_path: None,
+                });
+            }
+        }
+        for path in index_files.keys() {
+            if worktree_files
+                .binary_search_by(|file| file.as_str().cmp(path))
+                .is_err()
+            {
+                unstaged.push(FileStatus {
+                    path: path.to_string(),
+                    change: Change::Deleted,
+                    old_path: None,
+                });
+            }
+        }
+        unstaged.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Self {
+            branch: storage.head_ref().map(|name| {
+                name.trim_start_matches("refs/heads/")
+                    .to_string()
+            }),
+            head: storage.head(),
+            staged,
+            unmerged,
+            unstaged,
+            untracked,
+        })
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty()
+            && self.unmerged.is_empty()
+            && self.unstaged.is_empty()
+            && self.untracked.is_empty()
+    }
+
+    pub fn write_long(&self, out: &mut dyn Write) -> Result<()> {
+        match (&self.branch, &self.head) {
+            (Some(branch), _) => writeln!(out, "On branch {}", branch)?,
+            (None, Some(head)) => {
+                writeln!(out, "HEAD detached at {}", head.short())?
+            }
+            (None, None) => writeln!(out, "Not currently on any branch.")?,
+        }
+        if self.head.is_none() {
+            writeln!(out, "\nNo commits yet")?;
+        }
+
+        if !self.unmerged.is_empty() {
+            writeln!(out, "\nYou have unmerged paths.")?;
+        }
+
+        write_section(out, "Changes to be committed:", &self.staged)?;
+        if !self.unmerged.is_empty() {
+            writeln!(out, "\nUnmerged paths:")?;
+            for file in &self.unmerged {
+                writeln!(out, "\t{:<17}{}", file.label(), file.path)?;
+            }
+        }
+        write_section(out, "Changes not staged for commit:", &self.unstaged)?;
+
+        if !self.untracked.is_empty() {
+            writeln!(out, "\nUntracked files:")?;
+            for path in &self.untracked {
+                writeln!(out, "\t{}", path)?;
+            }
+        }
+
+        if self.is_clean() {
+            writeln!(out, "\nnothing to commit, working tree clean")?;
+        } else if self.staged.is_empty() {
+            writeln!(out, "\nno changes added to commit")?;
+        }
+        Ok(())
+    }
+
+    // "XY path" per file, X the staged and Y the unstaged change, with
+    // untracked files last as "?? path"
+    pub fn write_porcelain(&self, out: &mut dyn Write) -> Result<()> {
+        let mut codes: BTreeMap<&str, ([char; 2], String)> = BTreeMap::new();
+        for file in &self.staged {
+            codes
+                .entry(&file.path)
+                .or_insert(([' '; 2], file.display_path()))
+                .0[0] = file.change.code();
+        }
+        for file in &self.unmerged {
+            codes.insert(&file.path, (file.code(), file.path.clone()));
+        }
+        for file in &self.unstaged {
+            codes
+                .entry(&file.path)
+                .or_insert(([' '; 2], file.display_path()))
+                .0[1] = file.change.code();
+        }
+
+        for ([x, y], path) in codes.into_values() {
+            writeln!(out, "{}{} {}", x, y, path)?;
+        }
+        for path in &self.untracked {
+            writeln!(out, "?? {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_section(
+    out: &mut dyn Write,
+    title: &str,
+    files: &[FileStatus],
+) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "\n{}", title)?;
+    for file in files {
+        writeln!(out, "\t{:<12}{}", file.change.label(), file.display_path())?;
+    }
+    Ok(())
+}
+
+// every file below `dir` ("" for the whole worktree) as a path relative to
+// the worktree root, e.g. "dir/file", sorted and skipping the repository
+// directory
+pub fn worktree_files(fs: &dyn FileSystem, dir: &str) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_string()];
+    while let Some(dir) = dirs.pop() {
+        // the root is "" rather than "/" to every vfs backend
+        let dir_path = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", dir)
+        };
+        for name in fs.read_dir(&dir_path)? {
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            if path == MOUNT {
+                continue;
+            }
+
+            if fs
+                .metadata(&format!("/{}", path))?
+                .file_type
+                == VfsFileType::Directory
+            {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+    use crate::{
+        Content, Entry, Expected, JsonStorage, Signature,
+        write_worktree_content,
+    };
+
+    const LONG: &str = "a file long enough to be paired as a rename\n";
+
+    // a repository whose HEAD commit, index and worktree all hold `files`
+    fn repo(files: &[(&str, &str)]) -> (JsonStorage, MemoryFS) {
+        let mut storage = JsonStorage::new();
+        let fs = MemoryFS::new();
+        for (path, content) in files {
+            stage(&mut storage, &fs, path, content);
+        }
+        let tree = storage.write_tree().unwrap();
+        let sig = Signature::new("A".into(), "a@b".into(), 0, 0);
+        let commit = storage
+            .hash_object(Object::commit(
+                "c1".to_string(),
+                tree,
+                Vec::new(),
+                sig.clone(),
+                sig,
+            ))
+            .unwrap();
+        storage
+            .update_ref("refs/heads/main", commit, &Expected::Any)
+            .unwrap();
+        (storage, fs)
+    }
+
+    // write `content` to `path` and stage it
+    fn stage(
+        storage: &mut JsonStorage,
+        fs: &MemoryFS,
+        path: &str,
+        content: &str,
+    ) {
+        storage
+            .update_index(Content::new(
+                path.to_string(),
+                content.as_bytes().to_vec(),
+            ))
+            .unwrap();
+        write_worktree_content(fs, path, content.as_bytes()).unwrap();
+    }
+
+    // every kind of change at once: staged, unstaged, both, a staged
+    // rename, a deletion, a conflict and an untracked file
+    fn changed() -> (JsonStorage, MemoryFS) {
+        let (mut storage, fs) = repo(&[
+            ("both", "1"),
+            ("conflict", "1"),
+            ("dirty", "1"),
+            ("gone", "1"),
+            ("kept", "1"),
+            ("old-name", LONG),
+            ("staged", "1"),
+        ]);
+        stage(&mut storage, &fs, "staged", "2");
+        stage(&mut storage, &fs, "both", "2");
+        write_worktree_content(&fs, "both", b"3").unwrap();
+        write_worktree_content(&fs, "dirty", b"2").unwrap();
+        fs.remove_file("/gone").unwrap();
+        write_worktree_content(&fs, "new", b"new").unwrap();
+
+        fs.remove_file("/old-name").unwrap();
+        storage
+            .modify_index(|index| {
+                index.remove("old-name");
+            })
+            .unwrap();
+        stage(&mut storage, &fs, "new-name", LONG);
+
+        let [base, ours, theirs] = ["1", "2", "3"]
+            .map(|content| Object::blob(content.as_bytes().to_vec()).hash());
+        storage
+            .modify_index(|index| {
+                index.remove("conflict");
+                index.insert(Entry::at_stage("conflict".into(), base, 1));
+                index.insert(Entry::at_stage("conflict".into(), ours, 2));
+                index.insert(Entry::at_stage("conflict".into(), theirs, 3));
+            })
+            .unwrap();
+        write_worktree_content(&fs, "conflict", b"<<<<<<< ours").unwrap();
+        (storage, fs)
+    }
+
+    fn paths(files: &[FileStatus]) -> Vec<(&str, Change)> {
+        files
+            .iter()
+            .map(|file| (file.path.as_str(), file.change))
+            .collect()
+    }
+
+    fn written(write: impl FnOnce(&mut dyn Write) -> Result<()>) -> String {
+        let mut out = Vec::new();
+        write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn status_sorts_each_change_into_its_list() {
+        let (storage, fs) = changed();
+        let status = Status::new(&storage, &fs).unwrap();
+
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.head, storage.head());
+        assert_eq!(
+            paths(&status.staged),
+            [
+                ("both", Change::Modified),
+                ("new-name", Change::Renamed),
+                ("staged", Change::Modified),
+            ]
+        );
+        assert_eq!(status.staged[1].old_path.as_deref(), Some("old-name"));
+        let unmerged: Vec<&str> = status
+            .unmerged
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(unmerged, ["conflict"]);
+        assert_eq!(
+            paths(&status.unstaged),
+            [
+                ("both", Change::Modified),
+                ("dirty", Change::Modified),
+                ("gone", Change::Deleted),
+            ]
+        );
+        assert_eq!(status.untracked, ["new"]);
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn porcelain_gives_a_line_per_file() {
+        let (storage, fs) = changed();
+        let status = Status::new(&storage, &fs).unwrap();
+        assert_eq!(
+            written(|out| status.write_porcelain(out)),
+            "MM both\n\
+             UU conflict\n \
+             M dirty\n \
+             D gone\n\
+             R  old-name -> new-name\n\
+             M  staged\n\
+             ?? new\n"
+        );
+    }
+
+    #[test]
+    fn long_format_has_a_section_per_list() {
+        let (storage, fs) = changed();
+        let status = Status::new(&storage, &fs).unwrap();
+        assert_eq!(
+            written(|out| status.write_long(out)),
+            "On branch main\n\
+             \n\
+             You have unmerged paths.\n\
+             \n\
+             Changes to be committed:\n\
+             \tmodified:   both\n\
+             \trenamed:    old-name -> new-name\n\
+             \tmodified:   staged\n\
+             \n\
+             Unmerged paths:\n\
+             \tboth modified:   conflict\n\
+             \n\
+             Changes not staged for commit:\n\
+             \tmodified:   both\n\
+             \tmodified:   dirty\n\
+             \tdeleted:    gone\n\
+             \n\
+             Untracked files:\n\
+             \tnew\n"
+        );
+    }
+
+    #[test]
+    fn a_clean_tree_says_so() {
+        let (storage, fs) = repo(&[("a", "1"), ("dir/b", "2")]);
+        let status = Status::new(&storage, &fs).unwrap();
+        assert!(status.is_clean());
+        assert_eq!(written(|out| status.write_porcelain(out)), "");
+        assert_eq!(
+            written(|out| status.write_long(out)),
+            "On branch main\n\nnothing to commit, working tree clean\n"
+        );
+    }
+
+    #[test]
+    fn a_new_repository_has_no_commits() {
+        let storage = JsonStorage::new();
+        let fs = MemoryFS::new();
+        write_worktree_content(&fs, "a", b"1").unwrap();
+        let status = Status::new(&storage, &fs).unwrap();
+        assert_eq!(status.head, None);
+        assert_eq!(
+            written(|out| status.write_long(out)),
+            "On branch main\n\
+             \n\
+             No commits yet\n\
+             \n\
+             Untracked files:\n\
+             \ta\n\
+             \n\
+             no changes added to commit\n"
+        );
+    }
+}
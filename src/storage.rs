@@ -1,179 +1,1037 @@
-use std::{fmt, path::PathBuf};
-
-use serde::{Deserialize, Serialize};
-use sha1::{
-    Digest, Sha1,
-    digest::{
-        consts::{U20, U64, U160},
-        generic_array::GenericArray,
-    },
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::{Path, PathBuf},
 };
 
-trait Storage {
-    fn write(&self, object: Object);
-    fn read(&self, object: Object);
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+use serde::{Serialize, de::DeserializeOwned};
+use vfs::{FileSystem, PhysicalFS, error::VfsErrorKind};
+
+use crate::{
+    Config, Content, Entry, Error, Expected, FsMap, Hash, Head, Index, Object,
+    ObjectMode, RefStore, ReflogEntry, Result, check_ref_name, parse_single,
+    short_ref_name,
+};
+
+// name of the repository directory at the root of a worktree
+pub const MOUNT: &str = ".g1t";
+
+// whether the worktree path `path`, e.g. "dir/file", lies in the mount and
+// so belongs to the repository rather than the worktree
+pub fn in_mount(path: &str) -> bool {
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        == Some(MOUNT)
 }
 
-pub enum Header {}
+// the object database plus the index, HEAD and config that sit beside it.
+// `Runner` is generic over this, so any backend can stand in for the disk.
+pub trait Storage {
+    // fails with `Error::MissingObject` when there is no such object
+    fn read_object(&self, hash: &Hash) -> Result<Object>;
+    // return object hash and store object
+    fn hash_object(&mut self, object: Object) -> Result<Hash>;
+    fn has_object(&self, hash: &Hash) -> Result<bool>;
+    // every stored object id, in ascending order
+    fn object_hashes(&self) -> Result<Box<dyn Iterator<Item = Hash> + '_>>;
+
+    fn index(&self) -> &Index;
+    fn modify_index(&mut self, modifier: impl FnOnce(&mut Index))
+    -> Result<()>;
+
+    // the commit HEAD resolves to, None on a branch with no commits yet
+    fn head(&self) -> Option<Hash>;
+    fn head_target(&self) -> &Head;
+    // repoint HEAD itself, leaving every branch alone
+    fn set_head_target(&mut self, head: Head) -> Result<()>;
 
-#[derive(Clone, Deserialize, Serialize)]
-struct Hash(Vec<u8>);
+    // `name` is a full ref name such as "refs/heads/main"
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>>;
+    // fails with `Error::RefUpdate` unless the ref matches `expected`
+    fn update_ref(
+        &mut self,
+        name: &str,
+        hash: Hash,
+        expected: &Expected,
+    ) -> Result<()>;
+    fn delete_ref(&mut self, name: &str, expected: &Expected) -> Result<()>;
+    // move `old` to `new`, which must not exist yet, with its reflog,
+    // taking HEAD along if it pointed at `old`
+    fn rename_ref(&mut self, old: &str, new: &str) -> Result<()>;
+    // every ref whose name starts with `prefix`, sorted by name
+    fn refs(&self, prefix: &str) -> Result<Vec<(String, Hash)>>;
+
+    // the values `name` ("HEAD" or a branch) has had, oldest first. Refs
+    // log their updates themselves; a deleted ref loses its log.
+    fn reflog(&self, name: &str) -> Result<Vec<ReflogEntry>>;
+    fn append_reflog(&mut self, name: &str, entry: ReflogEntry) -> Result<()>;
+
+    fn config(&self) -> &Config;
+    fn modify_config(
+        &mut self,
+        modifier: impl FnOnce(&mut Config),
+    ) -> Result<()>;
 
-impl fmt::Debug for Hash {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.0)
+    // the branch ref HEAD points at, e.g. "refs/heads/main"; None when
+    // HEAD is detached
+    fn head_ref(&self) -> Option<&str> {
+        match self.head_target() {
+            Head::Symbolic(name) => Some(name),
+            Head::Detached(_) => None,
+        }
     }
-}
 
-impl Hash {
-    fn from_vec(vec: Vec<u8>) -> Self {
-        Hash(vec)
+    // advance the branch HEAD points at, or HEAD itself when detached.
+    // Fails if the branch moved since HEAD was read.
+    fn set_head(&mut self, hash: Hash) -> Result<()> {
+        match self.head_target().clone() {
+            Head::Symbolic(name) => {
+                let expected = Expected::from_current(self.head());
+                self.update_ref(&name, hash, &expected)
+            }
+            Head::Detached(_) => self.set_head_target(Head::Detached(hash)),
+        }
+    }
+
+    // fails with `Error::RefUpdate` if `name` already exists
+    fn create_ref(&mut self, name: &str, hash: Hash) -> Result<()> {
+        self.update_ref(name, hash, &Expected::Missing)
+    }
+
+    // object ids starting with `prefix`, backends with an on-disk fan-out
+    // can avoid the full scan
+    fn hashes_with_prefix(&self, prefix: &str) -> Result<Vec<Hash>> {
+        Ok(self
+            .object_hashes()?
+            .filter(|hash| hash.to_hex().starts_with(prefix))
+            .collect())
+    }
+
+    // expand a full or abbreviated (at least 4 digits) hex object id
+    fn resolve(&self, prefix: &str) -> Result<Hash> {
+        let prefix = prefix.to_lowercase();
+        if !(4..=40).contains(&prefix.len())
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(Error::BadRevision(prefix));
+        }
+
+        let mut matches = self.hashes_with_prefix(&prefix)?;
+        match matches.len() {
+            0 => Err(Error::BadRevision(prefix)),
+            1 => Ok(matches.remove(0)),
+            _ => Err(Error::AmbiguousRevision(prefix, matches)),
+        }
+    }
+
+    // the object a revision expression such as "main~2", "HEAD^2",
+    // "v1.0:src/lib.rs" or "@{1}" names; see `rev_parse`
+    fn resolve_rev(&self, rev: &str) -> Result<Hash> {
+        parse_single(self, rev)
+    }
+
+    // the full name of the existing ref a short one such as "main" or
+    // "tags/v1.0" stands for, looked up the way git does
+    fn full_ref_name(&self, name: &str) -> Result<Option<String>> {
+        // only names under refs/ are looked up, so no other repository
+        // file can pass for a ref
+        for full in [
+            name.to_string(),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+        ] {
+            if full.starts_with("refs/")
+                && check_ref_name(&full).is_ok()
+                && self.read_ref(&full)?.is_some()
+            {
+                return Ok(Some(full));
+            }
+        }
+        Ok(None)
+    }
+
+    // the commit a plain name is: HEAD, a ref or an object id
+    fn resolve_name(&self, name: &str) -> Result<Hash> {
+        if name == "HEAD" || name == "@" {
+            return self
+                .head()
+                .ok_or_else(|| Error::BadRevision(name.to_string()));
+        }
+        if let Some(full) = self.full_ref_name(name)?
+            && let Some(hash) = self.read_ref(&full)?
+        {
+            return Ok(hash);
+        }
+        self.resolve(name)
+    }
+
+    // every blob below the tree `hash`, keyed by its path from there
+    fn flatten_tree(&self, hash: &Hash) -> Result<BTreeMap<String, Hash>> {
+        let mut files = BTreeMap::new();
+        flatten_subtree(self, hash, "", &mut files)?;
+        Ok(files)
+    }
+
+    // the files of the commit HEAD points at, empty before the first commit
+    fn head_files(&self) -> Result<BTreeMap<String, Hash>> {
+        let Some(head) = self.head() else {
+            return Ok(BTreeMap::new());
+        };
+        match self.read_object(&head)? {
+            Object::Commit { tree_hash, .. } => self.flatten_tree(&tree_hash),
+            _ => Err(Error::CorruptObject(head)),
+        }
+    }
+
+    // register file content to index, storing its blob
+    fn update_index(&mut self, content: Content) -> Result<()> {
+        let hash = self.hash_object(Object::blob(content.content))?;
+        self.modify_index(|index| {
+            index.insert(Entry::new(content.file_name, hash));
+        })
+    }
+
+    // write the index out as one tree object per directory and return the
+    // hash of the root tree. Fails with `Error::Unmerged` while merge
+    // conflicts are unresolved.
+    fn write_tree(&mut self) -> Result<Hash> {
+        let unmerged = self.index().unmerged();
+        if !unmerged.is_empty() {
+            return Err(Error::Unmerged(
+                unmerged
+                    .into_iter()
+                    .map(|unmerged| unmerged.path)
+                    .collect(),
+            ));
+        }
+
+        let entries: Vec<(String, Hash)> = self
+            .index()
+            .entries()
+            .iter()
+            .map(|entry| {
+                (entry.file_name().to_string(), entry.blob_hash().clone())
+            })
+            .collect();
+
+        write_subtree(self, "", &entries)
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum Object {
-    Blob {
-        hash: Hash,
-        content: String,
-    },
-    Tree {
-        hash: Hash,
-        contents: Vec<(String, Hash)>,
-    },
-    Commit {
-        hash: Hash,
-        message: String,
-        tree_hash: Hash,
-        parent_commit: Option<Hash>,
-    },
+// the reflog entry for `name` moving from `old` to `new`, signed as the
+// committer
+fn reflog_entry<S: Storage + ?Sized>(
+    storage: &S,
+    old: Option<Hash>,
+    new: Hash,
+    message: String,
+) -> ReflogEntry {
+    ReflogEntry {
+        old,
+        new,
+        who: storage.config().signature("committer"),
+        message,
+    }
 }
 
-impl Object {
-    fn blob(content: String) -> Self {
-        let hash: Hash = Hash(Sha1::digest(content.clone()).to_vec());
+// record that the ref `name` moved from `old` to `new`, in HEAD's log too
+// when HEAD is on it. Only branches and other refs under refs/ are logged.
+pub(crate) fn log_ref_update<S: Storage + ?Sized>(
+    storage: &mut S,
+    name: &str,
+    old: Option<Hash>,
+    new: Hash,
+) -> Result<()> {
+    if old.as_ref() == Some(&new) || !name.starts_with("refs/") {
+        return Ok(());
+    }
+    let message = match old {
+        None => "created".to_string(),
+        Some(_) => "updated".to_string(),
+    };
+    let entry = reflog_entry(storage, old, new, message);
+    if storage.head_ref() == Some(name) {
+        storage.append_reflog("HEAD", entry.clone())?;
+    }
+    storage.append_reflog(name, entry)
+}
 
-        Object::Blob { hash, content }
+// record that `old` was renamed to `new`, which still points at `hash`, in
+// the log it took along and in HEAD's when HEAD went with it
+pub(crate) fn log_ref_rename<S: Storage + ?Sized>(
+    storage: &mut S,
+    old: &str,
+    new: &str,
+    hash: Hash,
+) -> Result<()> {
+    if !new.starts_with("refs/") {
+        return Ok(());
+    }
+    let message = format!("Branch: renamed {} to {}", old, new);
+    let entry = reflog_entry(storage, Some(hash.clone()), hash, message);
+    if storage.head_ref() == Some(new) {
+        storage.append_reflog("HEAD", entry.clone())?;
     }
+    storage.append_reflog(new, entry)
+}
 
-    fn tree(contents: Vec<(String, Hash)>) -> Self {
-        Object::Tree {
-            contents: contents.clone(),
+// record HEAD moving from `old` to what it points at now
+pub(crate) fn log_head_update<S: Storage + ?Sized>(
+    storage: &mut S,
+    old: (Head, Option<Hash>),
+) -> Result<()> {
+    let Some(new) = storage.head() else {
+        return Ok(());
+    };
+    let describe = |head: &Head| match head {
+        Head::Symbolic(name) => short_ref_name(name).to_string(),
+        Head::Detached(hash) => hash.to_hex(),
+    };
+    // a detached HEAD moving along with new commits is no checkout
+    let switched = match (&old.0, storage.head_target()) {
+        (Head::Detached(_), Head::Detached(_)) => false,
+        (old, new) => old != new,
+    };
+    let message = match switched {
+        false if old.1.as_ref() == Some(&new) => return Ok(()),
+        false => "updated".to_string(),
+        true => format!(
+            "checkout: moving from {} to {}",
+            describe(&old.0),
+            describe(storage.head_target())
+        ),
+    };
+    let entry = reflog_entry(storage, old.1, new, message);
+    storage.append_reflog("HEAD", entry)
+}
+
+fn flatten_subtree<S: Storage + ?Sized>(
+    storage: &S,
+    hash: &Hash,
+    prefix: &str,
+    files: &mut BTreeMap<String, Hash>,
+) -> Result<()> {
+    let Object::Tree { contents, .. } = storage.read_object(hash)? else {
+        return Err(Error::CorruptObject(hash.clone()));
+    };
 
-            hash: contents[0].1.clone(),
+    for (name, mode, hash) in contents {
+        let path = format!("{}{}", prefix, name);
+        match mode {
+            ObjectMode::Blob => {
+                files.insert(path, hash);
+            }
+            ObjectMode::Tree => {
+                flatten_subtree(storage, &hash, &format!("{}/", path), files)?
+            }
+            // submodules have no content of their own here
+            ObjectMode::Commit => {}
         }
     }
+    Ok(())
+}
 
-    fn commit(
-        message: String,
-        tree_hash: Hash,
-        parent_commit: Option<Hash>,
-    ) -> Self {
-        Object::Commit {
-            hash: Hash(
-                Sha1::digest(format!("{} {:?}", message, tree_hash)).to_vec(),
-            ),
-            message,
-            tree_hash,
-            parent_commit,
+// the tree for `entries`, whose paths are relative to the directory
+// `prefix` ("" or e.g. "dir/"). A name that is both a file and a directory
+// would make a tree git calls corrupt, so it is refused.
+fn write_subtree<S: Storage + ?Sized>(
+    storage: &mut S,
+    prefix: &str,
+    entries: &[(String, Hash)],
+) -> Result<Hash> {
+    let mut contents = Vec::new();
+    let mut dirs: BTreeMap<&str, Vec<(String, Hash)>> = BTreeMap::new();
+
+    for (path, hash) in entries {
+        match path.split_once('/') {
+            Some((dir, rest)) => dirs
+                .entry(dir)
+                .or_default()
+                .push((rest.to_string(), hash.clone())),
+            None => {
+                contents.push((path.clone(), ObjectMode::Blob, hash.clone()))
+            }
         }
     }
 
-    fn hash(&self) -> Hash {
-        match self {
-            Object::Blob { hash, .. } => hash.clone(),
-            Object::Tree { hash, .. } => hash.clone(),
-            Object::Commit { hash, .. } => hash.clone(),
-        }
+    if let Some((name, ..)) = contents
+        .iter()
+        .find(|(name, ..)| dirs.contains_key(name.as_str()))
+    {
+        return Err(Error::CorruptFile(
+            "index".to_string(),
+            format!("'{}{}' is both a file and a directory", prefix, name),
+        ));
+    }
+
+    for (dir, children) in dirs {
+        let prefix = format!("{}{}/", prefix, dir);
+        let hash = write_subtree(storage, &prefix, &children)?;
+        contents.push((dir.to_string(), ObjectMode::Tree, hash));
     }
+
+    storage.hash_object(Object::tree(contents))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Index {
-    entries: Vec<Entry>,
+// keeps everything in memory, for tests and throwaway repositories
+#[derive(Debug)]
+pub struct JsonStorage {
+    index: Index,
+    objects: BTreeMap<Hash, Object>,
+    head: Head,
+    refs: BTreeMap<String, Hash>,
+    reflogs: BTreeMap<String, Vec<ReflogEntry>>,
+    config: Config,
 }
 
-impl Index {
+impl JsonStorage {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for JsonStorage {
+    fn default() -> Self {
         Self {
-            entries: Vec::new(),
+            index: Index::default(),
+            objects: BTreeMap::new(),
+            head: Head::Symbolic("refs/heads/main".to_string()),
+            refs: BTreeMap::new(),
+            reflogs: BTreeMap::new(),
+            config: Config::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Entry {
-    blob_hash: Hash,
-    file_name: String,
+impl Storage for JsonStorage {
+    fn read_object(&self, hash: &Hash) -> Result<Object> {
+        self.objects
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| Error::MissingObject(hash.clone()))
+    }
+
+    fn hash_object(&mut self, object: Object) -> Result<Hash> {
+        let hash = object.hash();
+        self.objects
+            .insert(hash.clone(), object);
+        Ok(hash)
+    }
+
+    fn has_object(&self, hash: &Hash) -> Result<bool> {
+        Ok(self.objects.contains_key(hash))
+    }
+
+    fn object_hashes(&self) -> Result<Box<dyn Iterator<Item = Hash> + '_>> {
+        Ok(Box::new(self.objects.keys().cloned()))
+    }
+
+    fn index(&self) -> &Index {
+        &self.index
+    }
+
+    fn modify_index(
+        &mut self,
+        modifier: impl FnOnce(&mut Index),
+    ) -> Result<()> {
+        modifier(&mut self.index);
+        Ok(())
+    }
+
+    fn head(&self) -> Option<Hash> {
+        match &self.head {
+            Head::Symbolic(name) => self.refs.get(name).cloned(),
+            Head::Detached(hash) => Some(hash.clone()),
+        }
+    }
+
+    fn head_target(&self) -> &Head {
+        &self.head
+    }
+
+    fn set_head_target(&mut self, head: Head) -> Result<()> {
+        if let Head::Symbolic(name) = &head {
+            check_ref_name(name)?;
+        }
+        let old = (std::mem::replace(&mut self.head, head), self.head());
+        log_head_update(self, old)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>> {
+        if name == "HEAD" {
+            return Ok(self.head());
+        }
+        check_ref_name(name)?;
+        Ok(self.refs.get(name).cloned())
+    }
+
+    fn update_ref(
+        &mut self,
+        name: &str,
+        hash: Hash,
+        expected: &Expected,
+    ) -> Result<()> {
+        check_ref_name(name)?;
+        expected.check(name, self.refs.get(name))?;
+        let old = self
+            .refs
+            .insert(name.to_string(), hash.clone());
+        log_ref_update(self, name, old, hash)
+    }
+
+    fn delete_ref(&mut self, name: &str, expected: &Expected) -> Result<()> {
+        check_ref_name(name)?;
+        let current = self.refs.get(name).ok_or_else(|| {
+            Error::RefUpdate(name.to_string(), "does not exist".to_string())
+        })?;
+        expected.check(name, Some(current))?;
+        self.refs.remove(name);
+        self.reflogs.remove(name);
+        Ok(())
+    }
+
+    fn rename_ref(&mut self, old: &str, new: &str) -> Result<()> {
+        check_ref_name(old)?;
+        check_ref_name(new)?;
+        let hash = self
+            .refs
+            .get(old)
+            .cloned()
+            .ok_or_else(|| {
+                Error::RefUpdate(old.to_string(), "does not exist".to_string())
+            })?;
+        Expected::Missing.check(new, self.refs.get(new))?;
+
+        self.refs.remove(old);
+        self.refs
+            .insert(new.to_string(), hash.clone());
+        if let Some(log) = self.reflogs.remove(old) {
+            self.reflogs
+                .insert(new.to_string(), log);
+        }
+        // HEAD moves without a checkout entry; the rename is logged instead
+        if self.head_ref() == Some(old) {
+            self.head = Head::Symbolic(new.to_string());
+        }
+        log_ref_rename(self, old, new, hash)
+    }
+
+    fn refs(&self, prefix: &str) -> Result<Vec<(String, Hash)>> {
+        Ok(self
+            .refs
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, hash)| (name.clone(), hash.clone()))
+            .collect())
+    }
+
+    fn reflog(&self, name: &str) -> Result<Vec<ReflogEntry>> {
+        Ok(self
+            .reflogs
+            .get(name)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn append_reflog(&mut self, name: &str, entry: ReflogEntry) -> Result<()> {
+        self.reflogs
+            .entry(name.to_string())
+            .or_default()
+            .push(entry);
+        Ok(())
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn modify_config(
+        &mut self,
+        modifier: impl FnOnce(&mut Config),
+    ) -> Result<()> {
+        modifier(&mut self.config);
+        Ok(())
+    }
 }
 
+// a repository laid out as files under `mount` like a .git directory:
+// zlib-compressed loose objects, refs and HEAD, plus JSON index and config
 #[derive(Debug)]
-pub struct AbsStorage {
-    objects: Vec<Object>,
+pub struct FsMapedJson {
     index: Index,
-    head: Option<Hash>,
+    objects: FsMap,
+    refs: RefStore,
+    // HEAD and the commit it resolves to, cached from disk
+    head: Head,
+    head_hash: Option<Hash>,
+    config: Config,
+    mount: PathBuf,
+    pub fs: Box<dyn FileSystem>,
 }
 
-impl Storage for AbsStorage {
-    fn write(&self, object: Object) {}
+// write into a sibling lock file and rename it over `path`, so a crash never
+// leaves a half-written file behind
+pub(crate) fn write_atomic(
+    fs: &dyn FileSystem,
+    path: &Path,
+    content: &[u8],
+) -> Result<()> {
+    let path = path.to_string_lossy();
+    let lock_path = format!("{}.lock", path);
+
+    let mut file = fs.create_file(&lock_path)?;
+    file.write_all(content)?;
+    drop(file);
 
-    fn read(&self, object: Object) {}
+    if let Err(err) = fs.move_file(&lock_path, &path) {
+        // backends without rename (e.g. MemoryFS) are written in place
+        if !matches!(err.kind(), VfsErrorKind::NotSupported) {
+            return Err(err.into());
+        }
+        let mut file = fs.create_file(&path)?;
+        file.write_all(content)?;
+        fs.remove_file(&lock_path)?;
+    }
+    Ok(())
 }
 
-pub struct Content {
-    file_name: String,
-    content: String,
+fn write_json(
+    fs: &dyn FileSystem,
+    path: &Path,
+    value: &impl Serialize,
+) -> Result<()> {
+    let json = serde_json::to_string_pretty(value).map_err(|err| {
+        Error::CorruptFile(path.display().to_string(), err.to_string())
+    })?;
+    write_atomic(fs, path, json.as_bytes())
 }
 
-impl Content {
-    pub fn new(file_name: String, content: String) -> Self {
-        Self { file_name, content }
+// None when there is no file at `path`
+fn read_json<T: DeserializeOwned>(
+    fs: &dyn FileSystem,
+    path: &Path,
+) -> Result<Option<T>> {
+    let path_str = path.to_string_lossy();
+    if !fs.exists(&path_str)? {
+        return Ok(None);
     }
+
+    let mut content = String::new();
+    fs.open_file(&path_str)?
+        .read_to_string(&mut content)?;
+
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|err| {
+            Error::CorruptFile(path.display().to_string(), err.to_string())
+        })
 }
 
-impl AbsStorage {
-    pub fn new() -> Self {
-        Self {
-            objects: Vec::new(),
-            index: Index::new(),
-            head: None,
+// None when there is no file at `path`
+pub(crate) fn read_text(
+    fs: &dyn FileSystem,
+    path: &Path,
+) -> Result<Option<String>> {
+    let path = path.to_string_lossy();
+    if !fs.exists(&path)? {
+        return Ok(None);
+    }
+
+    let mut content = String::new();
+    fs.open_file(&path)?
+        .read_to_string(&mut content)?;
+    Ok(Some(content))
+}
+
+// like `mkdir -p`, creating every missing ancestor of `path`
+pub(crate) fn create_dir_all(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    for dir in path
+        .ancestors()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        let dir = dir.to_string_lossy();
+        if dir != "/" && !fs.exists(&dir)? {
+            fs.create_dir(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+impl FsMapedJson {
+    // open the repository at `mount`, which `init` must have laid out
+    pub fn new(mount: PathBuf, fs: Box<dyn FileSystem>) -> Result<Self> {
+        let refs = RefStore::new(&mount);
+        let head = refs.head(fs.as_ref())?;
+        let head_hash = refs.read("HEAD", fs.as_ref())?;
+
+        let index = read_json(fs.as_ref(), &mount.join("index.json"))?
+            .unwrap_or_default();
+
+        let objects = FsMap::new(mount.join("objects"));
+
+        let config = read_json(fs.as_ref(), &mount.join("config.json"))?
+            .unwrap_or_default();
+
+        Ok(Self {
+            index,
+            objects,
+            refs,
+            head,
+            head_hash,
+            config,
+            mount,
+            fs,
+        })
+    }
+
+    // open the repository at `mount` in the directory `root` on disk, where
+    // refs can be locked atomically
+    pub fn on_disk(root: &Path, mount: PathBuf) -> Result<Self> {
+        let mut storage = Self::new(mount, Box::new(PhysicalFS::new(root)))?;
+        storage.refs = RefStore::on_disk(&storage.mount, root);
+        Ok(storage)
+    }
+
+    pub fn is_repository(mount: &Path, fs: &dyn FileSystem) -> Result<bool> {
+        Ok(fs.exists(&mount.join("HEAD").to_string_lossy())?
+            && fs.exists(&mount.join("objects").to_string_lossy())?)
+    }
+
+    // lay out a new repository at `mount` with HEAD on `initial_branch`.
+    // An existing repository is opened untouched; any other non-empty
+    // directory is refused. Bare repositories get no index.
+    pub fn init(
+        mount: PathBuf,
+        fs: Box<dyn FileSystem>,
+        bare: bool,
+        initial_branch: &str,
+    ) -> Result<Self> {
+        let mount_str = mount.to_string_lossy();
+        if Self::is_repository(&mount, fs.as_ref())? {
+            return Self::new(mount, fs);
         }
+        if fs.exists(&mount_str)?
+            && fs
+                .read_dir(&mount_str)?
+                .next()
+                .is_some()
+        {
+            return Err(Error::NotEmpty(mount));
+        }
+
+        create_dir_all(fs.as_ref(), &mount.join("objects"))?;
+        create_dir_all(fs.as_ref(), &mount.join("refs").join("heads"))?;
+        create_dir_all(fs.as_ref(), &mount.join("refs").join("tags"))?;
+
+        RefStore::new(&mount).set_head(
+            &Head::Symbolic(format!("refs/heads/{}", initial_branch)),
+            fs.as_ref(),
+        )?;
+
+        let mut config = Config::new();
+        config.set("core.bare", bare.to_string());
+        write_json(fs.as_ref(), &mount.join("config.json"), &config)?;
+
+        if !bare {
+            write_json(
+                fs.as_ref(),
+                &mount.join("index.json"),
+                &Index::default(),
+            )?;
+        }
+
+        Self::new(mount, fs)
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.config.get("core.bare") == Some("true")
+    }
+
+    // refresh the cached HEAD after a ref changed
+    fn reload_head(&mut self) -> Result<()> {
+        self.head = self.refs.head(self.fs.as_ref())?;
+        self.head_hash = self
+            .refs
+            .read("HEAD", self.fs.as_ref())?;
+        Ok(())
+    }
+
+    // discard the in-memory index and read it back from disk, picking up
+    // changes made by other processes
+    pub fn reload_index(&mut self) -> Result<()> {
+        self.index =
+            read_json(self.fs.as_ref(), &self.mount.join("index.json"))?
+                .unwrap_or_default();
+        Ok(())
+    }
+}
+
+impl Storage for FsMapedJson {
+    fn read_object(&self, hash: &Hash) -> Result<Object> {
+        let compressed = self
+            .objects
+            .get(hash.clone(), self.fs.as_ref())?
+            .ok_or_else(|| Error::MissingObject(hash.clone()))?;
+
+        let mut data = Vec::new();
+        ZlibDecoder::new(compressed.as_slice())
+            .read_to_end(&mut data)
+            .map_err(|_| Error::CorruptObject(hash.clone()))?;
+
+        Object::decode(&data).ok_or_else(|| Error::CorruptObject(hash.clone()))
     }
 
-    fn hash_object(&mut self, object: Object) -> Hash {
+    // objects are stored zlib-compressed in their canonical encoding, the
+    // same way git writes loose objects
+    fn hash_object(&mut self, object: Object) -> Result<Hash> {
         let hash = object.hash();
-        self.objects.push(object);
-        hash
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&object.encode())?;
+
+        self.objects
+            .insert(hash.clone(), &encoder.finish()?, &mut self.fs)?;
+        Ok(hash)
+    }
+
+    fn has_object(&self, hash: &Hash) -> Result<bool> {
+        self.objects
+            .contains(hash, self.fs.as_ref())
+    }
+
+    fn object_hashes(&self) -> Result<Box<dyn Iterator<Item = Hash> + '_>> {
+        Ok(Box::new(
+            self.objects
+                .keys(self.fs.as_ref())?
+                .into_iter(),
+        ))
+    }
+
+    fn hashes_with_prefix(&self, prefix: &str) -> Result<Vec<Hash>> {
+        self.objects
+            .keys_with_prefix(prefix, self.fs.as_ref())
+    }
+
+    fn index(&self) -> &Index {
+        &self.index
+    }
+
+    fn modify_index(
+        &mut self,
+        modifier: impl FnOnce(&mut Index),
+    ) -> Result<()> {
+        modifier(&mut self.index);
+        write_json(
+            self.fs.as_ref(),
+            &self.mount.join("index.json"),
+            &self.index,
+        )
+    }
+
+    fn head(&self) -> Option<Hash> {
+        self.head_hash.clone()
+    }
+
+    fn head_target(&self) -> &Head {
+        &self.head
+    }
+
+    fn set_head_target(&mut self, head: Head) -> Result<()> {
+        let old = (self.head.clone(), self.head_hash.clone());
+        self.refs
+            .set_head(&head, self.fs.as_ref())?;
+        self.reload_head()?;
+        log_head_update(self, old)
     }
 
-    pub fn update_index(&mut self, contents: Vec<Content>) {
-        for content in contents {
-            let blob_hash = self.hash_object(Object::blob(content.content));
-            self.index.entries.push(Entry {
-                blob_hash,
-                file_name: content.file_name,
-            });
+    fn read_ref(&self, name: &str) -> Result<Option<Hash>> {
+        self.refs.read(name, self.fs.as_ref())
+    }
+
+    fn update_ref(
+        &mut self,
+        name: &str,
+        hash: Hash,
+        expected: &Expected,
+    ) -> Result<()> {
+        let old = self.read_ref(name)?;
+        self.refs
+            .update(name, &hash, expected, self.fs.as_ref())?;
+        self.reload_head()?;
+        log_ref_update(self, name, old, hash)
+    }
+
+    fn delete_ref(&mut self, name: &str, expected: &Expected) -> Result<()> {
+        self.refs
+            .delete(name, expected, self.fs.as_ref())?;
+        self.refs
+            .delete_log(name, self.fs.as_ref())?;
+        self.reload_head()
+    }
+
+    fn rename_ref(&mut self, old: &str, new: &str) -> Result<()> {
+        let fs = self.fs.as_ref();
+        let hash = self
+            .refs
+            .read(old, fs)?
+            .ok_or_else(|| {
+                Error::RefUpdate(old.to_string(), "does not exist".to_string())
+            })?;
+
+        self.refs
+            .update(new, &hash, &Expected::Missing, fs)?;
+        self.refs.rename_log(old, new, fs)?;
+        self.refs
+            .delete(old, &Expected::Is(hash.clone()), fs)?;
+        // HEAD moves without a checkout entry; the rename is logged instead
+        if self.head_ref() == Some(old) {
+            self.refs
+                .set_head(&Head::Symbolic(new.to_string()), fs)?;
         }
+        self.reload_head()?;
+        log_ref_rename(self, old, new, hash)
+    }
+
+    fn refs(&self, prefix: &str) -> Result<Vec<(String, Hash)>> {
+        self.refs.list(prefix, self.fs.as_ref())
+    }
+
+    fn reflog(&self, name: &str) -> Result<Vec<ReflogEntry>> {
+        self.refs
+            .read_log(name, self.fs.as_ref())
     }
 
-    pub fn commit(&mut self, message: impl Into<String>) {
-        let mut contents = Vec::new();
+    fn append_reflog(&mut self, name: &str, entry: ReflogEntry) -> Result<()> {
+        self.refs
+            .append_log(name, &entry, self.fs.as_ref())
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn modify_config(
+        &mut self,
+        modifier: impl FnOnce(&mut Config),
+    ) -> Result<()> {
+        modifier(&mut self.config);
+        write_json(
+            self.fs.as_ref(),
+            &self.mount.join("config.json"),
+            &self.config,
+        )
+    }
+}
 
-        for entry in self.index.entries.iter() {
-            let blob_hash = entry.blob_hash.clone();
-            let file_name = entry.file_name.clone();
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
 
-            contents.push((file_name, blob_hash));
+    use super::*;
+    use crate::Signature;
+
+    fn messages(storage: &impl Storage, name: &str) -> Vec<String> {
+        storage
+            .reflog(name)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    // renaming the branch HEAD is on and then another one, the same on
+    // every backend
+    fn renames_take_the_reflog_along(storage: &mut impl Storage) {
+        let tree = storage
+            .hash_object(Object::tree(Vec::new()))
+            .unwrap();
+        let sig = Signature::new("A".into(), "a@b".into(), 0, 0);
+        let [c1, c2] = ["c1", "c2"].map(|message| {
+            let commit = Object::commit(
+                message.to_string(),
+                tree.clone(),
+                Vec::new(),
+                sig.clone(),
+                sig.clone(),
+            );
+            storage.hash_object(commit).unwrap()
+        });
+        for hash in [&c1, &c2] {
+            storage
+                .update_ref("refs/heads/main", hash.clone(), &Expected::Any)
+                .unwrap();
         }
+        storage
+            .create_ref("refs/heads/side", c1.clone())
+            .unwrap();
 
-        let tree = Object::tree(contents);
-        let tree_hash = self.hash_object(tree);
+        storage
+            .rename_ref("refs/heads/main", "refs/heads/trunk")
+            .unwrap();
+        assert_eq!(
+            storage
+                .read_ref("refs/heads/main")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .read_ref("refs/heads/trunk")
+                .unwrap(),
+            Some(c2)
+        );
+        assert_eq!(storage.head_ref(), Some("refs/heads/trunk"));
+        let renamed = "Branch: renamed refs/heads/main to refs/heads/trunk";
+        assert_eq!(
+            messages(storage, "refs/heads/trunk"),
+            ["created", "updated", renamed]
+        );
+        assert!(messages(storage, "refs/heads/main").is_empty());
+        // HEAD went along without a checkout
+        assert_eq!(messages(storage, "HEAD"), ["created", "updated", renamed]);
 
-        let commit =
-            Object::commit(message.into(), tree_hash, self.head.clone());
+        storage
+            .rename_ref("refs/heads/side", "refs/heads/topic/side")
+            .unwrap();
+        assert_eq!(
+            messages(storage, "refs/heads/topic/side"),
+            [
+                "created",
+                "Branch: renamed refs/heads/side to refs/heads/topic/side"
+            ]
+        );
+        assert_eq!(messages(storage, "HEAD").len(), 3);
 
-        self.head = Some(commit.hash());
+        // the new name has to be free
+        let taken =
+            storage.rename_ref("refs/heads/trunk", "refs/heads/topic/side");
+        assert!(matches!(taken, Err(Error::RefUpdate(..))));
+        assert_eq!(storage.head_ref(), Some("refs/heads/trunk"));
+        assert_eq!(messages(storage, "refs/heads/trunk").len(), 3);
+    }
+
+    #[test]
+    fn renaming_in_memory_takes_the_reflog_along() {
+        renames_take_the_reflog_along(&mut JsonStorage::new());
+    }
 
-        self.hash_object(commit);
+    #[test]
+    fn renaming_on_files_takes_the_reflog_along() {
+        let mut storage = FsMapedJson::init(
+            format!("/{}", MOUNT).into(),
+            Box::new(MemoryFS::new()),
+            false,
+            "main",
+        )
+        .unwrap();
+        renames_take_the_reflog_along(&mut storage);
     }
 }